@@ -0,0 +1,3 @@
+#!/bin/sh
+# 一个pub,100个sub,每条消息512K
+ cargo run  --release  -- --urls 127.0.0.1:4222 --subject test --num-subs 100 --num-msgs 1000 --msg-size 524288
\ No newline at end of file
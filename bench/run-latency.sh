@@ -0,0 +1,5 @@
+#!/bin/sh
+# 十个pub不停地发小消息,同时一个低频的pub在单独的线程中测量往返延迟
+# 和不让出比较的时候server用 NATS_CONFIG 指向 {"yield_msgs":0,"yield_bytes":0}
+# 1个cpu,release: 默认 p99 15.1~15.3ms,不让出 p99 330~466ms,见readme
+ cargo run  --release  -- --urls 127.0.0.1:4222 --subject test --num-subs 1 --num-msgs 10000000 --num-pubs 10 --msg-size 16 --latency-probes 1000
@@ -160,6 +160,42 @@ pub struct Benchmark {
     name: String,
     pubs: SampleGroup,
     subs: SampleGroup,
+    latencies: LatencyGroup,
+}
+// LatencyGroup holds the round trip times of a low-rate publisher measured while the others are running
+#[derive(Debug, Default, Clone)]
+pub struct LatencyGroup {
+    samples: Vec<Duration>,
+}
+impl LatencyGroup {
+    pub fn add_sample(&mut self, d: Duration) {
+        self.samples.push(d);
+    }
+    pub fn has_samples(&self) -> bool {
+        !self.samples.is_empty()
+    }
+    // Percentile returns the nearest-rank percentile, p is in [0,100]
+    pub fn percentile(&self, p: usize) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::from_secs(0);
+        }
+        let mut sorted = self.samples.clone();
+        sorted.sort();
+        let rank = (p * sorted.len() + 99) / 100;
+        sorted[max(rank, 1) - 1]
+    }
+}
+impl std::fmt::Display for LatencyGroup {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(
+            f,
+            "{} round trips, p50 {:?}, p99 {:?}, max {:?}",
+            self.samples.len(),
+            self.percentile(50),
+            self.percentile(99),
+            self.percentile(100),
+        )
+    }
 }
 
 impl Benchmark {
@@ -169,6 +205,7 @@ impl Benchmark {
             name: name.into(),
             pubs: Default::default(),
             subs: Default::default(),
+            latencies: Default::default(),
         }
     }
     pub fn add_pub_sample(&mut self, s: Sample) {
@@ -179,6 +216,9 @@ impl Benchmark {
         self.bench_sample.add_statistics(&s);
         self.subs.add_sample(s);
     }
+    pub fn add_latency_sample(&mut self, d: Duration) {
+        self.latencies.add_sample(d);
+    }
     // Report returns a human readable report of the samples taken in the Benchmark
     pub fn report(&self) -> String {
         let mut buf = BytesMut::with_capacity(1024);
@@ -225,6 +265,9 @@ impl Benchmark {
             }
         }
         let _ = write!(buf, "{} {}\n", indent, self.subs.statistics());
+        if self.latencies.has_samples() {
+            let _ = write!(buf, "{}Latency stats: {}\n", indent, self.latencies);
+        }
         String::from_utf8(buf.to_vec()).unwrap()
     }
     pub fn csv(&self) -> String {
@@ -338,6 +381,18 @@ mod tests {
         println!("csv\n{}", csv);
     }
     #[test]
+    fn test_percentile() {
+        let mut lg = LatencyGroup::default();
+        assert_eq!(lg.percentile(99), Duration::from_secs(0));
+        for i in (1..=100).rev() {
+            lg.add_sample(Duration::from_millis(i));
+        }
+        assert_eq!(lg.percentile(0), Duration::from_millis(1));
+        assert_eq!(lg.percentile(50), Duration::from_millis(50));
+        assert_eq!(lg.percentile(99), Duration::from_millis(99));
+        assert_eq!(lg.percentile(100), Duration::from_millis(100));
+    }
+    #[test]
     fn test_report() {
         let bench = make_bench(2, 3);
         let r = bench.report();
@@ -1,12 +1,14 @@
 mod bench;
 mod wait_group;
 use crate::bench::{msgs_per_client, Benchmark, Sample};
+use bytes::BytesMut;
 use client::client::Client;
+use protocol::encode_pub;
 use std::error::Error;
 use std::sync::Arc;
 use structopt::StructOpt;
-use tokio::sync::{oneshot, Mutex};
-use tokio::time::Instant;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::{Duration, Instant};
 use wait_group::WaitGroup;
 
 /// benchmark for simple nats
@@ -34,6 +36,12 @@ struct Opt {
     ///publish subject
     #[structopt(long, default_value = "test_subject")]
     subject: String,
+    ///Number of round trips measured by a low-rate publisher while the others are running
+    #[structopt(long, default_value = "0")]
+    latency_probes: usize,
+    ///Interval between two latency probes in milliseconds
+    #[structopt(long, default_value = "10")]
+    latency_interval_ms: u64,
 }
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -77,7 +85,15 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }
     start_wg.wait().await;
     println!("pubs all started.");
+    let probe = if opt.latency_probes > 0 {
+        Some(spawn_latency_probe(opt.clone(), bench.clone()))
+    } else {
+        None
+    };
     done_wg.wait().await;
+    if let Some(probe) = probe {
+        let _ = probe.await;
+    }
     println!("all task stopped.");
     println!("{}\n", bench.lock().await.report());
     if opt.csv_file.len() > 0 {
@@ -125,11 +141,14 @@ async fn run_publiser(
         msgs.clear();
         subjects.clear();
     }
+    //实际发出去的字节数,包括PUB控制行
+    let mut frame = BytesMut::new();
+    encode_pub(&mut frame, opt.subject.as_str(), None, None, msg.as_slice());
     let s = Sample::new(
         num_msgs,
         opt.msg_size,
         num_msgs as u64,
-        (num_msgs * opt.msg_size) as u64,
+        (num_msgs * frame.len()) as u64,
         start,
         Instant::now(),
     );
@@ -182,5 +201,57 @@ async fn run_subscriber(
     println!("subsriber done");
     done_wg.done().await;
 }
+/*
+低频的publisher在单独的线程和runtime中运行,
+否则测出来的主要是它和bench自己的publisher抢同一个runtime的时间,而不是server的延迟
+*/
+fn spawn_latency_probe(opt: Opt, bench: Arc<Mutex<Benchmark>>) -> oneshot::Receiver<()> {
+    let (tx, rx) = oneshot::channel();
+    std::thread::spawn(move || {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(run_latency_probe(opt, bench));
+        let _ = tx.send(());
+    });
+    rx
+}
+//订阅自己发布的主题,测量从发布到收到的往返时间
+async fn run_latency_probe(opt: Opt, bench: Arc<Mutex<Benchmark>>) {
+    let mut c = Client::connect(opt.urls.as_str()).await.unwrap();
+    let subject = format!("{}.latency", opt.subject);
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let r = c
+        .sub_message(
+            subject.clone(),
+            None,
+            Box::new(move |_| {
+                let _ = tx.send(Instant::now());
+                Ok(())
+            }),
+        )
+        .await;
+    if let Err(e) = r {
+        println!("latency probe sub error {}", e);
+        return;
+    }
+    let interval = Duration::from_millis(opt.latency_interval_ms);
+    for _ in 0..opt.latency_probes {
+        let start = Instant::now();
+        if let Err(e) = c.pub_message(subject.as_str(), b"x").await {
+            println!("latency probe pub error {}", e);
+            break;
+        }
+        match rx.recv().await {
+            Some(end) => bench.lock().await.add_latency_sample(end - start),
+            None => break,
+        }
+        tokio::time::delay_for(interval).await;
+    }
+    c.close();
+    println!("latency probe done");
+}
 #[test]
 fn test() {}
@@ -1,13 +1,16 @@
-use crate::parser::Parser;
-use crate::parser::*;
-use bytes::buf::BufMutExt;
-use bytes::{Buf, BytesMut};
+use bytes::BytesMut;
+use log::{error, info, warn};
+use protocol::{encode_pub, ClientCodec, ClientOp, ServerOp, SubArg};
+pub use protocol::{ConnectInfo as ConnectOptions, ServerInfo};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::io::*;
 use tokio::net::TcpStream;
 use tokio::sync::{oneshot, Mutex};
+use tokio_util::codec::{Decoder, Encoder};
 
+//每次从连接上至少读多少数据
+const READ_BUF_LEN: usize = 64 * 1024;
 type MessageHandler = Box<dyn FnMut(&[u8]) -> std::result::Result<(), ()> + Sync + Send>;
 //#[derive(Debug)]
 pub struct Client {
@@ -16,22 +19,42 @@ pub struct Client {
     msg_buf: Option<BytesMut>,
     pub stop: Option<oneshot::Sender<()>>,
     sid: u64,
+    //按照sid找到订阅,带通配符的订阅收到的消息是publisher发布的主题
     handler: Arc<Mutex<HashMap<String, MessageHandler>>>,
+    server_info: ServerInfo,
 }
 
 impl Client {
     pub async fn connect(addr: &str) -> std::io::Result<Client> {
+        Self::connect_with_options(addr, ConnectOptions::default()).await
+    }
+    //CONNECT消息格式为CONNECT {"verbose":false,"echo":true}\r\n
+    pub async fn connect_with_options(
+        addr: &str,
+        options: ConnectOptions,
+    ) -> std::io::Result<Client> {
         let conn = TcpStream::connect(addr).await?;
-        let (reader, writer) = tokio::io::split(conn);
+        let (reader, mut writer) = tokio::io::split(conn);
+        let mut buf = BytesMut::new();
+        ClientCodec::new()
+            .encode(ClientOp::Connect(options), &mut buf)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+        writer.write_all(&buf).await?;
         let (tx, rx) = tokio::sync::oneshot::channel();
+        let (info_tx, info_rx) = tokio::sync::oneshot::channel();
         let msg_sender = Arc::new(Mutex::new(HashMap::new()));
         let writer = Arc::new(Mutex::new(writer));
         tokio::spawn(Self::receive_task(
             reader,
             rx,
+            info_tx,
             msg_sender.clone(),
             writer.clone(),
         ));
+        //服务器连接以后首先会发送INFO,收到以后才算连接成功
+        let server_info = info_rx.await.map_err(|_| {
+            std::io::Error::new(ErrorKind::ConnectionAborted, "server did not send INFO")
+        })?;
         return Ok(Client {
             addr: addr.to_string(),
             writer,
@@ -39,74 +62,109 @@ impl Client {
             sid: 0,
             handler: msg_sender,
             msg_buf: Some(BytesMut::with_capacity(512)),
+            server_info,
         });
     }
+    pub fn server_info(&self) -> &ServerInfo {
+        &self.server_info
+    }
+    //超过服务器max_payload的消息发出去也会导致连接被服务器断开,所以在本地就拒绝
+    fn check_payload(&self, msg: &[u8]) -> std::io::Result<()> {
+        if msg.len() > self.server_info.max_payload {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "message size {} exceeds max payload {}",
+                    msg.len(),
+                    self.server_info.max_payload
+                ),
+            ));
+        }
+        Ok(())
+    }
     async fn receive_task(
         mut reader: ReadHalf<TcpStream>,
         stop: oneshot::Receiver<()>,
+        info: oneshot::Sender<ServerInfo>,
         handler: Arc<Mutex<HashMap<String, MessageHandler>>>,
         writer: Arc<Mutex<WriteHalf<TcpStream>>>,
     ) {
         use futures::*;
-        let mut buf = [0 as u8; 512];
-        let mut parser = Parser::new();
+        let mut buf = BytesMut::with_capacity(READ_BUF_LEN);
+        let mut codec = ClientCodec::new();
         let mut stop = stop.fuse();
+        let mut info = Some(info);
         //        let mut _r: Result<usize>;
         loop {
+            //split_frame已经为不完整的大消息预留了空间,这里只保证每次至少能读一批
+            if buf.capacity() - buf.len() < READ_BUF_LEN / 4 {
+                buf.reserve(READ_BUF_LEN);
+            }
             select! {
                             _=stop=>{
-                            println!("client stoped.");
+                            info!("client stoped.");
                             let r=writer.lock().await.shutdown().await;
                              if r.is_err() {
-                                println!("receive_task err {:?}", r.unwrap_err());
+                                error!("receive_task err {:?}", r.unwrap_err());
                                 return;
                             }
                             return;
                             },
-                              r = reader.read(&mut buf[..]).fuse()=>{
+                              r = reader.read_buf(&mut buf).fuse()=>{
                             if r.is_err() {
-                                println!("receive_task err {:?}", r.unwrap_err());
+                                error!("receive_task err {:?}", r.unwrap_err());
                                 return;
                             }
                             let r = r.unwrap();
                             if r == 0 {
-                                println!("connection closed");
+                                info!("connection closed");
                                 return;
                             }
-                            let mut buf = &buf[0..r];
-            //                println!("read buf len={},buf={}", r, unsafe {
-            //                    std::str::from_utf8_unchecked(buf)
-            //                });
                             loop {
-                                let r = parser.parse(buf);
-                                if r.is_err() {
-                                    println!("msg error:{}", r.unwrap_err());
-                                    let r=writer.lock().await.shutdown().await;
-                                    if r.is_err() {
-                                        println!("shutdown err {:?}",r);
-                                    }
-                                    return;
-                                }
-                                let (r, n) = r.unwrap();
-                                if let ParseResult::MsgArg(ref msg) = r {
-                                    if let Some(handler) = handler.lock().await.get_mut(msg.subject) {
-                                        let r = handler(msg.msg);
+                                let op = match codec.decode(&mut buf) {
+                                    Ok(Some(op)) => op,
+                                    Ok(None) => break,
+                                    Err(e) => {
+                                        error!("msg error:{}", e);
+                                        let r=writer.lock().await.shutdown().await;
                                         if r.is_err() {
-                                            println!("handler error {:?}", r.unwrap_err());
+                                            error!("shutdown err {:?}",r);
+                                        }
+                                        return;
+                                    }
+                                };
+                                match op {
+                                    ServerOp::Msg(msg) | ServerOp::Hmsg { arg: msg, .. } => {
+                                        if let Some(handler) = handler.lock().await.get_mut(&msg.sid) {
+                                            let r = handler(&msg.payload);
+                                            if r.is_err() {
+                                                error!("handler error {:?}", r.unwrap_err());
+                                                return;
+                                            }
+                                        } else {
+                                            warn!("receive msg on subject {} sid {}, not found receiver", msg.subject, msg.sid);
+                                        }
+                                    }
+                                    ServerOp::Info(server_info) => {
+                                        //server马上就要关闭了,已经建立的连接还可以继续用一段时间
+                                        if server_info.ldm {
+                                            warn!("server entered lame duck mode");
+                                        }
+                                        codec.set_max_payload(server_info.max_payload);
+                                        if let Some(info) = info.take() {
+                                            let _ = info.send(server_info);
+                                        }
+                                    }
+                                    //比如订阅超过了限制,连接还是可以继续使用的
+                                    ServerOp::Err(e) => warn!("server error: {}", e),
+                                    ServerOp::Ping => {
+                                        if let Err(e) = writer.lock().await.write_all(b"PONG\r\n").await {
+                                            error!("send pong err {:?}", e);
                                             return;
                                         }
-                                    } else {
-                                        println!("receive msg on subject {}, not found receiver", msg.subject);
                                     }
-                                    parser.clear_msg_buf();
-                                } else if ParseResult::NoMsg == r {
-                                    break; //NoMsg
-                                }
-            //                    println!("n={},buf len={}", n, buf.len());
-                                if n == buf.len() {
-                                    break;
+                                    ServerOp::Pong | ServerOp::Ok => {}
                                 }
-                                buf = &buf[n..];
                             }
                         }
                              }
@@ -114,42 +172,27 @@ impl Client {
     }
     //pub消息格式为PUB subject size\r\n{message}
     pub async fn pub_message(&mut self, subject: &str, msg: &[u8]) -> std::io::Result<()> {
-        use std::io::Write;
-        let msg_buf = self.msg_buf.take().expect("must have");
-        let mut writer = msg_buf.writer();
-        writer.write("PUB ".as_bytes())?;
-        writer.write(subject.as_bytes())?;
-        //        write!(writer, subject)?;
-        write!(writer, " {}\r\n", msg.len())?;
-        writer.write(msg)?; //todo 这个需要copy么?最好别copy
-        writer.write("\r\n".as_bytes())?;
-        let mut msg_buf = writer.into_inner();
-        let mut writer = self.writer.lock().await;
-        writer.write_all(msg_buf.bytes()).await?;
+        self.check_payload(msg)?;
+        let mut msg_buf = self.msg_buf.take().expect("must have");
+        encode_pub(&mut msg_buf, subject, None, None, msg);
+        let r = self.writer.lock().await.write_all(&msg_buf).await;
         msg_buf.clear();
         self.msg_buf = Some(msg_buf);
-        Ok(())
+        r
     }
     //批量pub,
     pub async fn pub_messages(&mut self, subjects: &[&str], msgs: &[&[u8]]) -> std::io::Result<()> {
-        use std::io::Write;
-        let msg_buf = self.msg_buf.take().expect("must have");
-        let mut writer = msg_buf.writer();
+        for msg in msgs {
+            self.check_payload(msg)?;
+        }
+        let mut msg_buf = self.msg_buf.take().expect("must have");
         for i in 0..subjects.len() {
-            writer.write("PUB ".as_bytes())?;
-            writer.write(subjects[i].as_bytes())?;
-            //        write!(writer, subject)?;
-            write!(writer, " {}\r\n", msgs[i].len())?;
-            writer.write(msgs[i])?; //todo 这个需要copy么?最好别copy
-            writer.write("\r\n".as_bytes())?;
+            encode_pub(&mut msg_buf, subjects[i], None, None, msgs[i]);
         }
-        let mut msg_buf = writer.into_inner();
-        let mut writer = self.writer.lock().await;
-
-        writer.write_all(msg_buf.bytes()).await?;
+        let r = self.writer.lock().await.write_all(&msg_buf).await;
         msg_buf.clear();
         self.msg_buf = Some(msg_buf);
-        Ok(())
+        r
     }
     //    type MessageHandler = Box<dyn Fn(&[u8]) -> Result<()> + Sync + Send >;
     //sub消息格式为SUB subject {queue} {sid}\r\n
@@ -161,20 +204,18 @@ impl Client {
         handler: MessageHandler,
     ) -> std::io::Result<()> {
         self.sid += 1;
-        let mut writer = self.writer.lock().await;
-        if let Some(q) = queue {
-            writer
-                .write_all(format!("SUB {} {} {}\r\n", subject, q, self.sid).as_bytes())
-                .await?;
-        } else {
-            writer
-                .write_all(format!("SUB {} {}\r\n", subject, self.sid).as_bytes())
-                .await?;
-        }
-        self.handler
-            .lock()
-            .await
-            .insert(subject.to_string(), handler);
+        let mut buf = BytesMut::new();
+        let sid = self.sid.to_string();
+        let sub = SubArg {
+            subject,
+            queue,
+            sid: sid.clone(),
+        };
+        ClientCodec::new()
+            .encode(ClientOp::Sub(sub), &mut buf)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+        self.writer.lock().await.write_all(&buf).await?;
+        self.handler.lock().await.insert(sid, handler);
         Ok(())
     }
     pub fn close(&mut self) {
@@ -208,6 +249,93 @@ mod tests {
     }
     #[test]
     fn test() {}
+    //模拟一个只发送INFO的服务器,超过max_payload的消息在本地就被拒绝
+    #[tokio::test]
+    async fn test_max_payload() {
+        use super::Client;
+        use tokio::io::AsyncWriteExt;
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            conn.write_all(b"INFO {\"max_payload\":8}\r\n")
+                .await
+                .unwrap();
+            let mut buf = [0; 1024];
+            while let Ok(n) = tokio::io::AsyncReadExt::read(&mut conn, &mut buf).await {
+                if n == 0 {
+                    break;
+                }
+            }
+        });
+        let mut c = Client::connect(addr.as_str()).await.unwrap();
+        assert_eq!(c.server_info().max_payload, 8);
+        assert!(c.pub_message("foo", b"").await.is_ok());
+        assert!(c.pub_message("foo", b"12345678").await.is_ok());
+        let e = c.pub_message("foo", b"123456789").await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        c.close();
+    }
+    //MSG中的主题是发布的主题,要按照sid找到订阅
+    #[tokio::test]
+    async fn test_route_by_sid() {
+        use super::Client;
+        use std::sync::{Arc, Mutex};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            conn.write_all(b"INFO {\"max_payload\":1024}\r\n")
+                .await
+                .unwrap();
+            let mut buf = Vec::new();
+            let mut tmp = [0; 1024];
+            while !String::from_utf8_lossy(&buf).contains("SUB foo.bar 2\r\n") {
+                let n = conn.read(&mut tmp).await.unwrap();
+                buf.extend_from_slice(&tmp[..n]);
+            }
+            conn.write_all(b"MSG foo.bar 2 1\r\na\r\nMSG foo.bar 1 1\r\nb\r\n")
+                .await
+                .unwrap();
+            while let Ok(n) = conn.read(&mut tmp).await {
+                if n == 0 {
+                    break;
+                }
+            }
+        });
+        let mut c = Client::connect(addr.as_str()).await.unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        for subject in ["foo.*", "foo.bar"].iter() {
+            let received = received.clone();
+            let subject = subject.to_string();
+            let handler = {
+                let subject = subject.clone();
+                Box::new(move |msg: &[u8]| {
+                    received
+                        .lock()
+                        .unwrap()
+                        .push((subject.clone(), msg.to_vec()));
+                    Ok(())
+                })
+            };
+            c.sub_message(subject, None, handler).await.unwrap();
+        }
+        for _ in 0..100 {
+            if received.lock().unwrap().len() == 2 {
+                break;
+            }
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![
+                ("foo.bar".to_string(), b"a".to_vec()),
+                ("foo.*".to_string(), b"b".to_vec())
+            ]
+        );
+        c.close();
+    }
     #[tokio::main]
     #[test]
     async fn test_2() {
@@ -1,4 +1,4 @@
 #![recursion_limit = "512"]
 pub mod client;
-pub mod error;
-mod parser;
+//错误码和server共用,见protocol
+pub use protocol::error;
@@ -0,0 +1,921 @@
+/*!
+ServerCodec用在server端,解码client发过来的ClientOp,编码发给client的ServerOp;
+ClientCodec用在client端,正好相反.
+*/
+use crate::error::*;
+use crate::ops::*;
+use crate::parser::*;
+use crate::DEFAULT_MAX_PAYLOAD;
+use bytes::{Bytes, BytesMut};
+use serde::Serialize;
+use tokio_util::codec::{Decoder, Encoder};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClientVerb {
+    Connect,
+    Pub,
+    Hpub,
+    Sub,
+    Unsub,
+    Ping,
+    Pong,
+}
+const CLIENT_VERBS: [(&[u8], ClientVerb); 7] = [
+    (b"CONNECT", ClientVerb::Connect),
+    (b"PUB", ClientVerb::Pub),
+    (b"HPUB", ClientVerb::Hpub),
+    (b"SUB", ClientVerb::Sub),
+    (b"UNSUB", ClientVerb::Unsub),
+    (b"PING", ClientVerb::Ping),
+    (b"PONG", ClientVerb::Pong),
+];
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ServerVerb {
+    Info,
+    Msg,
+    Hmsg,
+    Ok,
+    Err,
+    Ping,
+    Pong,
+}
+const SERVER_VERBS: [(&[u8], ServerVerb); 7] = [
+    (b"INFO", ServerVerb::Info),
+    (b"MSG", ServerVerb::Msg),
+    (b"HMSG", ServerVerb::Hmsg),
+    (b"+OK", ServerVerb::Ok),
+    (b"-ERR", ServerVerb::Err),
+    (b"PING", ServerVerb::Ping),
+    (b"PONG", ServerVerb::Pong),
+];
+//长度为0的消息也是合法的,比如心跳
+fn check_payload(size: usize, max_payload: usize) -> Result<()> {
+    if size > max_payload {
+        return Err(NError::new(ERROR_MESSAGE_SIZE_TOO_LARGE));
+    }
+    Ok(())
+}
+//PING,PONG,+OK后面不能有参数
+fn no_args(args: &str) -> Result<()> {
+    if !args.is_empty() {
+        parse_error!();
+    }
+    Ok(())
+}
+fn parse_json<'a, T: serde::Deserialize<'a>>(args: &'a str) -> Result<T> {
+    serde_json::from_str(args).map_err(|_| NError::new(ERROR_PARSE))
+}
+/**
+server端的codec,解码ClientOp,编码ServerOp
+*/
+pub struct ServerCodec {
+    max_payload: usize,
+    //不为None的时候打印每一个解析出来的控制行,内容是日志的前缀,一般是连接的信息
+    trace: Option<String>,
+}
+impl ServerCodec {
+    pub fn new(max_payload: usize) -> Self {
+        Self {
+            max_payload,
+            trace: None,
+        }
+    }
+    //server重新加载配置以后,已有的连接也按照新的设置来
+    pub fn set_max_payload(&mut self, max_payload: usize) {
+        self.max_payload = max_payload;
+    }
+    pub fn set_trace(&mut self, trace: Option<String>) {
+        self.trace = trace;
+    }
+    pub fn is_trace(&self) -> bool {
+        self.trace.is_some()
+    }
+    //根据控制行计算整条消息的长度,包括消息体以及结尾的\r\n
+    fn frame_len(&self, buf: &[u8]) -> Result<Option<usize>> {
+        let line = match control_line(&CLIENT_VERBS, buf)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let size = match line.op {
+            ClientVerb::Pub => parse_body_args(line.args, 1, false)?.total_len,
+            ClientVerb::Hpub => parse_body_args(line.args, 1, true)?.total_len,
+            _ => return Ok(Some(line.len)),
+        };
+        check_payload(size, self.max_payload)?;
+        Ok(Some(line.len + size + 2))
+    }
+    fn parse_frame(&self, frame: Bytes) -> Result<ClientOp> {
+        let line = match control_line(&CLIENT_VERBS, &frame)? {
+            Some(line) => line,
+            None => parse_error!(),
+        };
+        if let Some(ref prefix) = self.trace {
+            trace_line(prefix, &frame[..line.len]);
+        }
+        let op = match line.op {
+            ClientVerb::Connect => ClientOp::Connect(parse_json(line.args)?),
+            ClientVerb::Pub | ClientVerb::Hpub => {
+                let has_headers = line.op == ClientVerb::Hpub;
+                let args = parse_body_args(line.args, 1, has_headers)?;
+                let mut payload = body(&frame, line.len)?;
+                let headers = payload.split_to(args.header_len);
+                let arg = PubArg {
+                    subject: args.args[0].to_string(),
+                    reply_to: args.reply_to.map(|s| s.to_string()),
+                    payload,
+                };
+                if has_headers {
+                    ClientOp::Hpub { arg, headers }
+                } else {
+                    ClientOp::Pub(arg)
+                }
+            }
+            ClientVerb::Sub => {
+                let mut args = [""; 3]; //如果没有queue,长度就是2,否则长度是3
+                let (queue, sid) = match split_args(line.args, &mut args)? {
+                    2 => (None, args[1]),
+                    3 => (Some(args[1].to_string()), args[2]),
+                    _ => parse_error!(),
+                };
+                ClientOp::Sub(SubArg {
+                    subject: args[0].to_string(),
+                    queue,
+                    sid: sid.to_string(),
+                })
+            }
+            ClientVerb::Unsub => {
+                let mut args = [""; 2];
+                let max_msgs = match split_args(line.args, &mut args)? {
+                    1 => None,
+                    2 => Some(parse_size(args[1])? as u64),
+                    _ => parse_error!(),
+                };
+                ClientOp::Unsub {
+                    sid: args[0].to_string(),
+                    max_msgs,
+                }
+            }
+            ClientVerb::Ping => {
+                no_args(line.args)?;
+                ClientOp::Ping
+            }
+            ClientVerb::Pong => {
+                no_args(line.args)?;
+                ClientOp::Pong
+            }
+        };
+        Ok(op)
+    }
+}
+impl Default for ServerCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_PAYLOAD)
+    }
+}
+impl Decoder for ServerCodec {
+    type Item = ClientOp;
+    type Error = NError;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ClientOp>> {
+        let total = self.frame_len(src)?;
+        match split_frame(src, total) {
+            Some(frame) => self.parse_frame(frame).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+impl Encoder for ServerCodec {
+    type Item = ServerOp;
+    type Error = NError;
+    fn encode(&mut self, item: ServerOp, dst: &mut BytesMut) -> Result<()> {
+        match item {
+            ServerOp::Info(info) => encode_json(dst, b"INFO ", &info)?,
+            ServerOp::Msg(arg) => encode_msg(
+                dst,
+                &arg.subject,
+                &arg.sid,
+                arg.reply_to.as_deref(),
+                None,
+                &arg.payload,
+            ),
+            ServerOp::Hmsg { arg, headers } => encode_msg(
+                dst,
+                &arg.subject,
+                &arg.sid,
+                arg.reply_to.as_deref(),
+                Some(&headers),
+                &arg.payload,
+            ),
+            ServerOp::Ok => dst.extend_from_slice(b"+OK\r\n"),
+            ServerOp::Err(e) => {
+                dst.extend_from_slice(b"-ERR '");
+                dst.extend_from_slice(e.as_bytes());
+                dst.extend_from_slice(b"'\r\n");
+            }
+            ServerOp::Ping => dst.extend_from_slice(b"PING\r\n"),
+            ServerOp::Pong => dst.extend_from_slice(b"PONG\r\n"),
+        }
+        Ok(())
+    }
+}
+/**
+client端的codec,解码ServerOp,编码ClientOp
+*/
+pub struct ClientCodec {
+    max_payload: usize,
+    trace: Option<String>,
+}
+impl ClientCodec {
+    //收到服务器的INFO之前,默认消息体最长1M
+    pub fn new() -> Self {
+        Self {
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            trace: None,
+        }
+    }
+    pub fn set_trace(&mut self, trace: Option<String>) {
+        self.trace = trace;
+    }
+    //收到服务器的INFO以后,按照服务器的设置来
+    pub fn set_max_payload(&mut self, max_payload: usize) {
+        self.max_payload = max_payload;
+    }
+    fn frame_len(&self, buf: &[u8]) -> Result<Option<usize>> {
+        let line = match control_line(&SERVER_VERBS, buf)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let size = match line.op {
+            ServerVerb::Msg => parse_body_args(line.args, 2, false)?.total_len,
+            ServerVerb::Hmsg => parse_body_args(line.args, 2, true)?.total_len,
+            _ => return Ok(Some(line.len)),
+        };
+        check_payload(size, self.max_payload)?;
+        Ok(Some(line.len + size + 2))
+    }
+    fn parse_frame(&self, frame: Bytes) -> Result<ServerOp> {
+        let line = match control_line(&SERVER_VERBS, &frame)? {
+            Some(line) => line,
+            None => parse_error!(),
+        };
+        if let Some(ref prefix) = self.trace {
+            trace_line(prefix, &frame[..line.len]);
+        }
+        let op = match line.op {
+            ServerVerb::Info => ServerOp::Info(parse_json(line.args)?),
+            ServerVerb::Msg | ServerVerb::Hmsg => {
+                let has_headers = line.op == ServerVerb::Hmsg;
+                let args = parse_body_args(line.args, 2, has_headers)?;
+                let mut payload = body(&frame, line.len)?;
+                let headers = payload.split_to(args.header_len);
+                let arg = MsgArg {
+                    subject: args.args[0].to_string(),
+                    sid: args.args[1].to_string(),
+                    reply_to: args.reply_to.map(|s| s.to_string()),
+                    payload,
+                };
+                if has_headers {
+                    ServerOp::Hmsg { arg, headers }
+                } else {
+                    ServerOp::Msg(arg)
+                }
+            }
+            ServerVerb::Ok => {
+                no_args(line.args)?;
+                ServerOp::Ok
+            }
+            //去掉错误描述两边的单引号
+            ServerVerb::Err => ServerOp::Err(line.args.trim_matches('\'').to_string()),
+            ServerVerb::Ping => {
+                no_args(line.args)?;
+                ServerOp::Ping
+            }
+            ServerVerb::Pong => {
+                no_args(line.args)?;
+                ServerOp::Pong
+            }
+        };
+        Ok(op)
+    }
+}
+impl Default for ClientCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Decoder for ClientCodec {
+    type Item = ServerOp;
+    type Error = NError;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ServerOp>> {
+        let total = self.frame_len(src)?;
+        match split_frame(src, total) {
+            Some(frame) => self.parse_frame(frame).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+impl Encoder for ClientCodec {
+    type Item = ClientOp;
+    type Error = NError;
+    fn encode(&mut self, item: ClientOp, dst: &mut BytesMut) -> Result<()> {
+        match item {
+            ClientOp::Connect(info) => encode_json(dst, b"CONNECT ", &info)?,
+            ClientOp::Pub(arg) => encode_pub(
+                dst,
+                &arg.subject,
+                arg.reply_to.as_deref(),
+                None,
+                &arg.payload,
+            ),
+            ClientOp::Hpub { arg, headers } => encode_pub(
+                dst,
+                &arg.subject,
+                arg.reply_to.as_deref(),
+                Some(&headers),
+                &arg.payload,
+            ),
+            ClientOp::Sub(arg) => {
+                let queue = arg.queue.as_deref();
+                encode_line(
+                    dst,
+                    b"SUB",
+                    &[Some(arg.subject.as_str()), queue, Some(arg.sid.as_str())],
+                );
+            }
+            ClientOp::Unsub { sid, max_msgs } => {
+                let max_msgs = max_msgs.map(|n| n.to_string());
+                encode_line(dst, b"UNSUB", &[Some(sid.as_str()), max_msgs.as_deref()]);
+            }
+            ClientOp::Ping => dst.extend_from_slice(b"PING\r\n"),
+            ClientOp::Pong => dst.extend_from_slice(b"PONG\r\n"),
+        }
+        Ok(())
+    }
+}
+//十进制的长度,避免走fmt
+fn put_size(dst: &mut BytesMut, mut n: usize) {
+    let mut buf = [0u8; 20];
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    dst.extend_from_slice(&buf[i..]);
+}
+//没有消息体的控制行,为None的参数跳过
+fn encode_line(dst: &mut BytesMut, verb: &[u8], args: &[Option<&str>]) {
+    dst.extend_from_slice(verb);
+    for arg in args.iter().flatten() {
+        dst.extend_from_slice(b" ");
+        dst.extend_from_slice(arg.as_bytes());
+    }
+    dst.extend_from_slice(b"\r\n");
+}
+//收到的控制行,不包括消息体
+fn trace_line(prefix: &str, line: &[u8]) {
+    log::trace!("{} <- {}", prefix, String::from_utf8_lossy(line).trim_end());
+}
+fn encode_json<T: Serialize>(dst: &mut BytesMut, verb: &[u8], value: &T) -> Result<()> {
+    let json = serde_json::to_vec(value).map_err(|_| NError::new(ERROR_PARSE))?;
+    dst.reserve(verb.len() + json.len() + 2);
+    dst.extend_from_slice(verb);
+    dst.extend_from_slice(&json);
+    dst.extend_from_slice(b"\r\n");
+    Ok(())
+}
+/**
+带消息体的命令,有header的时候控制行最后两个参数分别是header长度和总长度
+```text
+MSG <subject> <sid> [reply-to] <size>\r\n<payload>\r\n
+HMSG <subject> <sid> [reply-to] <header size> <total size>\r\n<headers><payload>\r\n
+```
+*/
+fn encode_body(
+    dst: &mut BytesMut,
+    verb: &[u8],
+    args: &[&str],
+    reply_to: Option<&str>,
+    headers: Option<&[u8]>,
+    payload: &[u8],
+) {
+    dst.reserve(payload.len() + 2);
+    encode_body_header(dst, verb, args, reply_to, headers, payload.len());
+    dst.extend_from_slice(payload); //经测试,如果这里不使用缓存,而是多个await,性能会大幅下降.
+    dst.extend_from_slice(b"\r\n");
+}
+//控制行和headers,payload以及最后的\r\n由调用者负责
+fn encode_body_header(
+    dst: &mut BytesMut,
+    verb: &[u8],
+    args: &[&str],
+    reply_to: Option<&str>,
+    headers: Option<&[u8]>,
+    payload_len: usize,
+) {
+    let header_len = headers.map(|h| h.len()).unwrap_or(0);
+    let total = header_len + payload_len;
+    //两个长度最多40个字节,再加上空格和\r\n
+    let args_len: usize = args.iter().map(|a| a.len() + 1).sum();
+    dst.reserve(
+        verb.len() + args_len + reply_to.map(|r| r.len() + 1).unwrap_or(0) + 48 + header_len,
+    );
+    dst.extend_from_slice(verb);
+    for arg in args {
+        dst.extend_from_slice(b" ");
+        dst.extend_from_slice(arg.as_bytes());
+    }
+    if let Some(reply_to) = reply_to {
+        dst.extend_from_slice(b" ");
+        dst.extend_from_slice(reply_to.as_bytes());
+    }
+    if headers.is_some() {
+        dst.extend_from_slice(b" ");
+        put_size(dst, header_len);
+    }
+    dst.extend_from_slice(b" ");
+    put_size(dst, total);
+    dst.extend_from_slice(b"\r\n");
+    if let Some(headers) = headers {
+        dst.extend_from_slice(headers);
+    }
+}
+/**
+server推送消息,不需要先构造ServerOp,避免每个订阅者都分配一次subject和sid
+*/
+pub fn encode_msg(
+    dst: &mut BytesMut,
+    subject: &str,
+    sid: &str,
+    reply_to: Option<&str>,
+    headers: Option<&[u8]>,
+    payload: &[u8],
+) {
+    let verb: &[u8] = if headers.is_some() { b"HMSG" } else { b"MSG" };
+    encode_body(dst, verb, &[subject, sid], reply_to, headers, payload);
+}
+/**
+只编码MSG的控制行和headers,后面还要跟上长度为payload_len的payload和\r\n.
+大的payload可以在多个订阅者之间共享,不用每个订阅者都拷贝一次.
+*/
+pub fn encode_msg_header(
+    dst: &mut BytesMut,
+    subject: &str,
+    sid: &str,
+    reply_to: Option<&str>,
+    headers: Option<&[u8]>,
+    payload_len: usize,
+) {
+    let verb: &[u8] = if headers.is_some() { b"HMSG" } else { b"MSG" };
+    encode_body_header(dst, verb, &[subject, sid], reply_to, headers, payload_len);
+}
+/**
+client发布消息,不需要先把payload拷贝到Bytes中
+```text
+PUB <subject> [reply-to] <size>\r\n<payload>\r\n
+HPUB <subject> [reply-to] <header size> <total size>\r\n<headers><payload>\r\n
+```
+*/
+pub fn encode_pub(
+    dst: &mut BytesMut,
+    subject: &str,
+    reply_to: Option<&str>,
+    headers: Option<&[u8]>,
+    payload: &[u8],
+) {
+    let verb: &[u8] = if headers.is_some() { b"HPUB" } else { b"PUB" };
+    encode_body(dst, verb, &[subject], reply_to, headers, payload);
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    //解码整个输入,要求正好用完
+    fn decode_all<D: Decoder<Error = NError>>(codec: &mut D, input: &str) -> Result<Vec<D::Item>> {
+        let mut buf = BytesMut::from(input);
+        let mut ops = Vec::new();
+        while let Some(op) = codec.decode(&mut buf)? {
+            ops.push(op);
+        }
+        if !buf.is_empty() {
+            parse_error!();
+        }
+        Ok(ops)
+    }
+    fn sub(subject: &str, queue: Option<&str>, sid: &str) -> Option<ClientOp> {
+        Some(ClientOp::Sub(SubArg {
+            subject: subject.to_string(),
+            queue: queue.map(|q| q.to_string()),
+            sid: sid.to_string(),
+        }))
+    }
+    fn hello(subject: &str, reply_to: Option<&str>) -> PubArg {
+        PubArg {
+            subject: subject.to_string(),
+            reply_to: reply_to.map(|r| r.to_string()),
+            payload: Bytes::from_static(b"hello"),
+        }
+    }
+    fn msg(subject: &str, sid: &str, reply_to: Option<&str>, payload: &'static [u8]) -> MsgArg {
+        MsgArg {
+            subject: subject.to_string(),
+            sid: sid.to_string(),
+            reply_to: reply_to.map(|r| r.to_string()),
+            payload: Bytes::from_static(payload),
+        }
+    }
+    /*
+    各种合法和非法的控制行,期望的结果为None表示应该解析出错
+    */
+    #[test]
+    fn test_client_ops() {
+        let no_echo = ConnectInfo {
+            echo: false,
+            ..Default::default()
+        };
+        let cases: Vec<(&str, Option<ClientOp>)> = vec![
+            ("SUB foo 1\r\n", sub("foo", None, "1")),
+            ("sub foo 1\r\n", sub("foo", None, "1")),
+            ("Sub foo q 1\r\n", sub("foo", Some("q"), "1")),
+            ("sUb\tfoo\t1\r\n", sub("foo", None, "1")),
+            ("SUB  foo \t q\t\t1 \r\n", sub("foo", Some("q"), "1")),
+            ("SUB foo 1\n", sub("foo", None, "1")),
+            (
+                "PUB foo 5\r\nhello\r\n",
+                Some(ClientOp::Pub(hello("foo", None))),
+            ),
+            (
+                "pub foo 5\r\nhello\r\n",
+                Some(ClientOp::Pub(hello("foo", None))),
+            ),
+            (
+                "PuB\tfoo\t5\r\nhello\r\n",
+                Some(ClientOp::Pub(hello("foo", None))),
+            ),
+            (
+                "pub  foo \t5 \r\nhello\r\n",
+                Some(ClientOp::Pub(hello("foo", None))),
+            ),
+            (
+                "PUB foo bar 5\r\nhello\r\n",
+                Some(ClientOp::Pub(hello("foo", Some("bar")))),
+            ),
+            (
+                "HPUB foo 12 17\r\nNATS/1.0\r\n\r\nhello\r\n",
+                Some(ClientOp::Hpub {
+                    arg: hello("foo", None),
+                    headers: Bytes::from_static(b"NATS/1.0\r\n\r\n"),
+                }),
+            ),
+            (
+                "CONNECT {}\r\n",
+                Some(ClientOp::Connect(ConnectInfo::default())),
+            ),
+            (
+                "connect\t {} \r\n",
+                Some(ClientOp::Connect(ConnectInfo::default())),
+            ),
+            (
+                "CoNnEcT {\"echo\":false}\r\n",
+                Some(ClientOp::Connect(no_echo)),
+            ),
+            (
+                "UNSUB 1\r\n",
+                Some(ClientOp::Unsub {
+                    sid: "1".to_string(),
+                    max_msgs: None,
+                }),
+            ),
+            (
+                "unsub 1 10\r\n",
+                Some(ClientOp::Unsub {
+                    sid: "1".to_string(),
+                    max_msgs: Some(10),
+                }),
+            ),
+            ("PING\r\n", Some(ClientOp::Ping)),
+            ("pong\r\n", Some(ClientOp::Pong)),
+            ("SUBfoo 1\r\n", None),
+            ("S UB foo 1\r\n", None),
+            ("SUB foo\r\n", None),
+            ("SUB a b c d\r\n", None),
+            ("XUB foo 1\r\n", None),
+            ("PUBLISH foo 5\r\nhello\r\n", None),
+            ("PUB foo\r\nhello\r\n", None),
+            ("PUB foo x\r\nhello\r\n", None),
+            ("PUB a b c 5\r\nhello\r\n", None),
+            ("PUB foo 5\r\nhello world\r\n", None),
+            ("HPUB foo 6 5\r\nhello\r\n", None),
+            ("CONNECTX {}\r\n", None),
+            ("CONNECT {\r\n", None),
+            ("UNSUB\r\n", None),
+            ("PING x\r\n", None),
+            (" SUB foo 1\r\n", None),
+        ];
+        for (input, expected) in cases {
+            let r = decode_all(&mut ServerCodec::default(), input);
+            match expected {
+                Some(expected) => {
+                    let r = r.unwrap_or_else(|e| panic!("input={:?} err={}", input, e));
+                    assert_eq!(r, vec![expected], "input={:?}", input);
+                }
+                None => assert!(r.is_err(), "input={:?} r={:?}", input, r),
+            }
+        }
+    }
+    #[test]
+    fn test_server_ops() {
+        let cases: Vec<(&str, Option<ServerOp>)> = vec![
+            (
+                "MSG foo 1 5\r\nhello\r\n",
+                Some(ServerOp::Msg(msg("foo", "1", None, b"hello"))),
+            ),
+            (
+                "msg foo 1 5\r\nhello\r\n",
+                Some(ServerOp::Msg(msg("foo", "1", None, b"hello"))),
+            ),
+            (
+                "MsG\tfoo\t1\t5\r\nhello\r\n",
+                Some(ServerOp::Msg(msg("foo", "1", None, b"hello"))),
+            ),
+            (
+                "MSG  foo \t1  5 \r\nhello\r\n",
+                Some(ServerOp::Msg(msg("foo", "1", None, b"hello"))),
+            ),
+            (
+                "MSG foo 1 bar 5\r\nhello\r\n",
+                Some(ServerOp::Msg(msg("foo", "1", Some("bar"), b"hello"))),
+            ),
+            (
+                "MSG foo 1 0\r\n\r\n",
+                Some(ServerOp::Msg(msg("foo", "1", None, b""))),
+            ),
+            (
+                "HMSG foo 1 12 17\r\nNATS/1.0\r\n\r\nhello\r\n",
+                Some(ServerOp::Hmsg {
+                    arg: msg("foo", "1", None, b"hello"),
+                    headers: Bytes::from_static(b"NATS/1.0\r\n\r\n"),
+                }),
+            ),
+            (
+                "-ERR 'invalid subject'\r\n",
+                Some(ServerOp::Err("invalid subject".to_string())),
+            ),
+            (
+                "-err\t 'duplicate sid' \r\n",
+                Some(ServerOp::Err("duplicate sid".to_string())),
+            ),
+            (
+                "INFO {\"max_payload\":8}\r\n",
+                Some(ServerOp::Info(ServerInfo {
+                    version: "".to_string(),
+                    client_id: 0,
+                    max_payload: 8,
+                    ldm: false,
+                })),
+            ),
+            (
+                "INFO {\"max_payload\":8,\"ldm\":true}\r\n",
+                Some(ServerOp::Info(ServerInfo {
+                    version: "".to_string(),
+                    client_id: 0,
+                    max_payload: 8,
+                    ldm: true,
+                })),
+            ),
+            ("+OK\r\n", Some(ServerOp::Ok)),
+            ("PING\r\n", Some(ServerOp::Ping)),
+            ("PONG\r\n", Some(ServerOp::Pong)),
+            ("MSGfoo 1 5\r\nhello\r\n", None),
+            ("M SG foo 1 5\r\nhello\r\n", None),
+            ("MSG foo 1 x\r\nhello\r\n", None),
+            ("MSG foo 1 2 3 5\r\nhello\r\n", None),
+            ("MSG foo 1 5\r\nhello world\r\n", None),
+            ("INFOX {}\r\n", None),
+            ("INFO {}\r\n", None),
+            ("-ER 'x'\r\n", None),
+            ("-EXX\r\n", None),
+        ];
+        for (input, expected) in cases {
+            let r = decode_all(&mut ClientCodec::default(), input);
+            match expected {
+                Some(expected) => {
+                    let r = r.unwrap_or_else(|e| panic!("input={:?} err={}", input, e));
+                    assert_eq!(r, vec![expected], "input={:?}", input);
+                }
+                None => assert!(r.is_err(), "input={:?} r={:?}", input, r),
+            }
+        }
+    }
+    #[test]
+    fn test_payload_size() {
+        let mut codec = ServerCodec::new(8);
+        let r = decode_all(&mut codec, "PUB foo 0\r\n\r\nPUB foo 8\r\n12345678\r\n").unwrap();
+        match &r[..] {
+            [ClientOp::Pub(empty), ClientOp::Pub(full)] => {
+                assert!(empty.payload.is_empty());
+                assert_eq!(full.payload.as_ref(), "12345678".as_bytes());
+            }
+            _ => assert!(false, "must be valid pub arg"),
+        }
+        let r = decode_all(&mut codec, "PUB foo 9\r\n123456789\r\n");
+        assert_eq!(r.unwrap_err().err_code, ERROR_MESSAGE_SIZE_TOO_LARGE);
+        //header也算在长度里面
+        let r = decode_all(&mut codec, "HPUB foo 4 9\r\nabcd12345\r\n");
+        assert_eq!(r.unwrap_err().err_code, ERROR_MESSAGE_SIZE_TOO_LARGE);
+
+        let mut codec = ClientCodec::new();
+        codec.set_max_payload(4);
+        assert!(decode_all(&mut codec, "MSG foo 1 4\r\nabcd\r\n").is_ok());
+        let r = decode_all(&mut codec, "MSG foo 1 5\r\nabcde\r\n");
+        assert_eq!(r.unwrap_err().err_code, ERROR_MESSAGE_SIZE_TOO_LARGE);
+    }
+    //逐字节收到数据,结果要和一次性解析完全一样
+    #[test]
+    fn test_partial() {
+        let input = "SUB foo 1\r\nPUB foo 5\r\nhello\r\nCONNECT {}\r\nPUB foo 0\r\n\r\nPING\r\n";
+        let expected = decode_all(&mut ServerCodec::default(), input).unwrap();
+        assert_eq!(expected.len(), 5);
+        let mut codec = ServerCodec::default();
+        let mut buf = BytesMut::new();
+        let mut ops = Vec::new();
+        for b in input.as_bytes() {
+            buf.extend_from_slice(&[*b]);
+            while let Some(op) = codec.decode(&mut buf).unwrap() {
+                ops.push(op);
+            }
+        }
+        assert_eq!(ops, expected);
+        //不完整的时候为整条消息预留好空间
+        let mut buf = BytesMut::from("PUB foo 5\r\nhel");
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 14);
+        assert!(buf.capacity() >= 18);
+        //消息体和读缓冲区共享内存
+        let ptr = buf.as_ptr();
+        buf.extend_from_slice(b"lo\r\n");
+        match codec.decode(&mut buf).unwrap() {
+            Some(ClientOp::Pub(arg)) => assert_eq!(arg.payload.as_ptr(), unsafe { ptr.add(11) }),
+            r => assert!(false, "must be valid pub arg {:?}", r),
+        }
+        //没有换行的时候,控制行太长也要尽早报错
+        let mut buf = BytesMut::from("SUB ");
+        buf.extend_from_slice(&[b'a'; crate::MAX_CONTROL_LINE]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+    //client编码的ClientOp,server要能原样解码出来,反之亦然
+    #[test]
+    fn test_roundtrip() {
+        let client_ops = vec![
+            ClientOp::Connect(ConnectInfo {
+                verbose: true,
+                name: Some("chat".to_string()),
+                echo: false,
+                user: Some("alice".to_string()),
+            }),
+            ClientOp::Pub(hello("foo", None)),
+            ClientOp::Pub(hello("foo", Some("_INBOX.1"))),
+            ClientOp::Hpub {
+                arg: hello("foo", Some("bar")),
+                headers: Bytes::from_static(b"NATS/1.0\r\nk: v\r\n\r\n"),
+            },
+            sub("foo.*", None, "1").unwrap(),
+            sub("foo.>", Some("q"), "2").unwrap(),
+            ClientOp::Unsub {
+                sid: "1".to_string(),
+                max_msgs: None,
+            },
+            ClientOp::Unsub {
+                sid: "2".to_string(),
+                max_msgs: Some(5),
+            },
+            ClientOp::Ping,
+            ClientOp::Pong,
+        ];
+        let mut buf = BytesMut::new();
+        let mut codec = ClientCodec::new();
+        for op in client_ops.iter() {
+            codec.encode(op.clone(), &mut buf).unwrap();
+        }
+        let mut codec = ServerCodec::default();
+        let mut ops = Vec::new();
+        while let Some(op) = codec.decode(&mut buf).unwrap() {
+            ops.push(op);
+        }
+        assert_eq!(ops, client_ops);
+
+        let server_ops = vec![
+            ServerOp::Info(ServerInfo {
+                version: "0.1.0".to_string(),
+                client_id: 3,
+                max_payload: 1024,
+                ldm: true,
+            }),
+            ServerOp::Msg(msg("foo", "1", None, b"hello")),
+            ServerOp::Msg(msg("foo", "1", Some("bar"), b"")),
+            ServerOp::Hmsg {
+                arg: msg("foo", "2", None, b"hello"),
+                headers: Bytes::from_static(b"NATS/1.0\r\n\r\n"),
+            },
+            ServerOp::Ok,
+            ServerOp::Err("maximum subscriptions exceeded".to_string()),
+            ServerOp::Ping,
+            ServerOp::Pong,
+        ];
+        let mut codec = ServerCodec::default();
+        for op in server_ops.iter() {
+            codec.encode(op.clone(), &mut buf).unwrap();
+        }
+        let mut codec = ClientCodec::new();
+        let mut ops = Vec::new();
+        while let Some(op) = codec.decode(&mut buf).unwrap() {
+            ops.push(op);
+        }
+        assert_eq!(ops, server_ops);
+    }
+    #[test]
+    fn test_encode() {
+        let mut buf = BytesMut::new();
+        encode_msg(&mut buf, "foo", "1", None, None, b"hello");
+        assert_eq!(buf.as_ref(), b"MSG foo 1 5\r\nhello\r\n");
+        buf.clear();
+        encode_pub(&mut buf, "foo", Some("bar"), Some(b"NATS/1.0\r\n\r\n"), b"");
+        assert_eq!(buf.as_ref(), b"HPUB foo bar 12 12\r\nNATS/1.0\r\n\r\n\r\n");
+        buf.clear();
+        //控制行和payload分开编码,结果必须和encode_msg一样
+        let headers: &[u8] = b"NATS/1.0\r\n\r\n";
+        encode_msg(&mut buf, "foo", "1", Some("bar"), Some(headers), b"hello");
+        let mut split = BytesMut::new();
+        encode_msg_header(&mut split, "foo", "1", Some("bar"), Some(headers), 5);
+        split.extend_from_slice(b"hello\r\n");
+        assert_eq!(buf, split);
+        buf.clear();
+        let info = ServerInfo {
+            version: "0.1.0".to_string(),
+            client_id: 3,
+            max_payload: 1048576,
+            ldm: false,
+        };
+        ServerCodec::default()
+            .encode(ServerOp::Info(info), &mut buf)
+            .unwrap();
+        assert_eq!(
+            buf.as_ref(),
+            "INFO {\"version\":\"0.1.0\",\"client_id\":3,\"max_payload\":1048576}\r\n".as_bytes()
+        );
+    }
+}
+#[cfg(test)]
+mod benchmark {
+    extern crate test;
+    use super::*;
+    use test::Bencher;
+    //n条消息体长度为size的PUB拼在一起
+    fn pub_messages(size: usize, n: usize) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        let payload = vec![b'x'; size];
+        for _ in 0..n {
+            encode_pub(&mut buf, "foo.bar", None, None, &payload);
+        }
+        buf.to_vec()
+    }
+    //模拟每次read读到chunk个字节
+    fn bench_decode(b: &mut Bencher, size: usize, n: usize, chunk: usize) {
+        let buf = pub_messages(size, n);
+        let mut codec = ServerCodec::default();
+        b.bytes = buf.len() as u64;
+        b.iter(|| {
+            let mut count = 0;
+            let mut read_buf = BytesMut::with_capacity(chunk);
+            for data in buf.chunks(chunk) {
+                read_buf.extend_from_slice(data);
+                while let Some(op) = codec.decode(&mut read_buf).unwrap() {
+                    if let ClientOp::Pub(arg) = op {
+                        count += arg.payload.len();
+                    }
+                }
+            }
+            assert_eq!(count, size * n);
+        });
+    }
+    #[bench]
+    fn bench_decode_small(b: &mut Bencher) {
+        bench_decode(b, 16, 1000, 64 * 1024);
+    }
+    #[bench]
+    fn bench_decode_large(b: &mut Bencher) {
+        bench_decode(b, 64 * 1024, 16, 64 * 1024);
+    }
+    #[bench]
+    fn bench_encode_msg_small(b: &mut Bencher) {
+        let payload = vec![b'x'; 16];
+        let mut buf = BytesMut::with_capacity(64 * 1024);
+        b.iter(|| {
+            buf.clear();
+            for _ in 0..1000 {
+                encode_msg(&mut buf, "foo.bar", "1", None, None, &payload);
+            }
+        });
+    }
+}
@@ -0,0 +1,70 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+pub type Result<T> = std::result::Result<T, NError>;
+pub const ERROR_PARSE: i32 = 1;
+pub const ERROR_MESSAGE_SIZE_TOO_LARGE: i32 = 2;
+pub const ERROR_INVALID_SUBJECT: i32 = 3;
+pub const ERROR_SUBSCRIBTION_NOT_FOUND: i32 = 4;
+pub const ERROR_CONNECTION_CLOSED: i32 = 5;
+pub const ERROR_SUBJECT_TOO_LONG: i32 = 6;
+pub const ERROR_SUBJECT_TOO_MANY_TOKENS: i32 = 7;
+pub const ERROR_MAX_SUBSCRIPTIONS_PER_CONNECTION: i32 = 8;
+pub const ERROR_MAX_SUBSCRIPTIONS: i32 = 9;
+pub const ERROR_DUPLICATE_SID: i32 = 10;
+pub const ERROR_AUTO_UNSUBSCRIBE_NOT_SUPPORTED: i32 = 11;
+pub const ERROR_WRITE_DEADLINE_EXCEEDED: i32 = 12;
+pub const ERROR_SLOW_CONSUMER: i32 = 13;
+pub const ERROR_KICKED: i32 = 14;
+pub const ERROR_INVALID_PUBLISH_SUBJECT: i32 = 15;
+pub const ERROR_UNKOWN_ERROR: i32 = 1000;
+#[derive(Debug)]
+pub struct NError {
+    pub err_code: i32,
+}
+impl NError {
+    pub fn new(err_code: i32) -> Self {
+        Self { err_code }
+    }
+    pub fn error_description(&self) -> &'static str {
+        match self.err_code {
+            ERROR_PARSE => return "parse error",
+            ERROR_MESSAGE_SIZE_TOO_LARGE => return "message size too large",
+            ERROR_INVALID_SUBJECT => return "invalid subject",
+            ERROR_SUBSCRIBTION_NOT_FOUND => return "subscription not found",
+            ERROR_CONNECTION_CLOSED => return "connection closed",
+            ERROR_SUBJECT_TOO_LONG => return "subject too long",
+            ERROR_SUBJECT_TOO_MANY_TOKENS => return "subject has too many tokens",
+            ERROR_MAX_SUBSCRIPTIONS_PER_CONNECTION => {
+                return "maximum subscriptions per connection exceeded"
+            }
+            ERROR_MAX_SUBSCRIPTIONS => return "maximum subscriptions exceeded",
+            ERROR_DUPLICATE_SID => return "duplicate sid",
+            ERROR_AUTO_UNSUBSCRIBE_NOT_SUPPORTED => return "auto unsubscribe not supported",
+            ERROR_WRITE_DEADLINE_EXCEEDED => return "write deadline exceeded",
+            ERROR_SLOW_CONSUMER => return "slow consumer",
+            ERROR_KICKED => return "kicked by server",
+            ERROR_INVALID_PUBLISH_SUBJECT => return "invalid publish subject",
+            _ => return "unkown error",
+        }
+    }
+}
+impl Error for NError {}
+//codec要求错误类型能从io::Error转换过来,读写出错都意味着连接已经不能用了
+impl From<std::io::Error> for NError {
+    fn from(_: std::io::Error) -> Self {
+        NError::new(ERROR_CONNECTION_CLOSED)
+    }
+}
+impl Display for NError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        write!(f, "NError[{},{}]", self.err_code, self.error_description())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test() {
+        println!("{}", NError::new(ERROR_PARSE));
+    }
+}
@@ -0,0 +1,23 @@
+/*!
+server和client共用的协议定义.
+
+ClientOp是client发给server的消息,ServerOp是server发给client的消息.
+server端用ServerCodec解码ClientOp,编码ServerOp;client端用ClientCodec,正好相反.
+
+命令不区分大小写,参数之间可以用任意多个空格或者tab分隔.
+*/
+#![cfg_attr(test, feature(test))]
+#[macro_use]
+mod parser;
+mod codec;
+pub mod error;
+mod ops;
+pub use codec::{encode_msg, encode_msg_header, encode_pub, ClientCodec, ServerCodec};
+pub use ops::*;
+//默认消息体最长1M,防止Dos攻击
+pub const DEFAULT_MAX_PAYLOAD: usize = 1024 * 1024;
+/*
+控制行的最大长度,必须能够将一个完整的主题以及参数放进去,
+主题本身的长度由server的Limits限制
+*/
+pub const MAX_CONTROL_LINE: usize = 4096;
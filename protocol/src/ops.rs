@@ -0,0 +1,93 @@
+use bytes::Bytes;
+use serde_derive::{Deserialize, Serialize};
+
+/**
+客户端通过CONNECT发送过来的选项,
+没有发送CONNECT的客户端使用默认值
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectInfo {
+    pub verbose: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    //为false的时候,自己发布的消息不会再推送给自己的订阅
+    pub echo: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+impl Default for ConnectInfo {
+    fn default() -> Self {
+        Self {
+            verbose: false,
+            name: None,
+            echo: true,
+            user: None,
+        }
+    }
+}
+/**
+连接建立以后服务器通过INFO告诉客户端的信息,
+客户端据此可以在本地就拒绝超过max_payload的消息.
+server进入lame duck模式的时候会再发一次INFO,ldm为true,客户端应该尽快换一个server
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub client_id: u64,
+    pub max_payload: usize,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub ldm: bool,
+}
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+///SUB <subject> [queue] <sid>
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubArg {
+    pub subject: String,
+    pub queue: Option<String>,
+    pub sid: String,
+}
+///PUB <subject> [reply-to] <size>
+#[derive(Debug, Clone, PartialEq)]
+pub struct PubArg {
+    pub subject: String,
+    pub reply_to: Option<String>,
+    pub payload: Bytes, //和读缓冲区共享内存,不需要拷贝
+}
+///MSG <subject> <sid> [reply-to] <size>
+#[derive(Debug, Clone, PartialEq)]
+pub struct MsgArg {
+    pub subject: String,
+    pub sid: String,
+    pub reply_to: Option<String>,
+    pub payload: Bytes,
+}
+/**
+client发给server的消息,
+HPUB中的headers是完整的header部分,包括开头的NATS/1.0以及结尾的空行
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientOp {
+    Connect(ConnectInfo),
+    Pub(PubArg),
+    Hpub { arg: PubArg, headers: Bytes },
+    Sub(SubArg),
+    Unsub { sid: String, max_msgs: Option<u64> },
+    Ping,
+    Pong,
+}
+///server发给client的消息
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerOp {
+    Info(ServerInfo),
+    Msg(MsgArg),
+    Hmsg { arg: MsgArg, headers: Bytes },
+    Ok,
+    Err(String), //错误描述,比如订阅超过了限制
+    Ping,
+    Pong,
+}
@@ -0,0 +1,167 @@
+/*!
+控制行的解析,ServerCodec和ClientCodec共用.
+
+解析的时候先用memchr找到控制行的结尾,再根据参数中的size一次性确定整个消息的长度,
+消息完整以后从读缓冲区中整块切出来,消息体直接共享读缓冲区的内存,不逐字节拷贝.
+*/
+use crate::error::*;
+use crate::MAX_CONTROL_LINE;
+use bytes::{Bytes, BytesMut};
+use memchr::memchr;
+macro_rules! parse_error {
+    ( ) => {{
+        return Err(NError::new(ERROR_PARSE));
+    }};
+}
+
+pub(crate) struct ControlLine<'a, T> {
+    pub op: T,
+    pub args: &'a str, //去掉了命令以及两边的空白
+    pub len: usize,    //整个控制行的长度,包括结尾的\n
+}
+fn is_space(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+//去掉控制行参数两边的空白以及结尾的\r
+fn trim(mut buf: &[u8]) -> &[u8] {
+    while let Some((b, rest)) = buf.split_first() {
+        if !is_space(*b) {
+            break;
+        }
+        buf = rest;
+    }
+    while let Some((b, rest)) = buf.split_last() {
+        if !is_space(*b) && *b != b'\r' {
+            break;
+        }
+        buf = rest;
+    }
+    buf
+}
+/**
+根据开头的命令判断是哪种消息,返回命令以及命令的长度,
+数据太少还判断不出来的时候返回None,比如只收到了"PU"
+*/
+fn parse_op<T: Copy>(ops: &[(&[u8], T)], buf: &[u8]) -> Result<Option<(T, usize)>> {
+    for (verb, op) in ops.iter() {
+        let n = buf.len().min(verb.len());
+        if !buf[..n].eq_ignore_ascii_case(&verb[..n]) {
+            continue;
+        }
+        if buf.len() <= verb.len() {
+            return Ok(None);
+        }
+        //sub stevenbai.top 3 是ok的,但是substevenbai.top 3就不允许
+        match buf[verb.len()] {
+            b' ' | b'\t' | b'\r' | b'\n' => return Ok(Some((*op, verb.len()))),
+            _ => parse_error!(),
+        }
+    }
+    parse_error!()
+}
+/**
+找到一个完整的控制行,控制行不完整的时候返回None,
+但是命令不对或者控制行太长会立即报错
+*/
+pub(crate) fn control_line<'a, T: Copy>(
+    ops: &[(&[u8], T)],
+    buf: &'a [u8],
+) -> Result<Option<ControlLine<'a, T>>> {
+    let (op, verb_len) = match parse_op(ops, buf)? {
+        Some(op) => op,
+        None => return Ok(None),
+    };
+    let line_end = match memchr(b'\n', buf) {
+        Some(pos) => pos,
+        None if buf.len() > MAX_CONTROL_LINE => parse_error!(),
+        None => return Ok(None),
+    };
+    if line_end > MAX_CONTROL_LINE {
+        parse_error!();
+    }
+    //有可能对方恶意发送一些无效的utf8字符
+    let args = std::str::from_utf8(trim(&buf[verb_len..line_end]))
+        .map_err(|_| NError::new(ERROR_PARSE))?;
+    Ok(Some(ControlLine {
+        op,
+        args,
+        len: line_end + 1,
+    }))
+}
+//按照空格或者tab切分参数,参数个数超过out的长度就是格式错误
+pub(crate) fn split_args<'a>(args: &'a str, out: &mut [&'a str]) -> Result<usize> {
+    let mut n = 0;
+    for s in args.split(|c| c == ' ' || c == '\t') {
+        if s.len() == 0 {
+            continue;
+        }
+        if n >= out.len() {
+            parse_error!();
+        }
+        out[n] = s;
+        n += 1;
+    }
+    Ok(n)
+}
+pub(crate) fn parse_size(s: &str) -> Result<usize> {
+    s.parse::<usize>().map_err(|_| NError::new(ERROR_PARSE))
+}
+pub(crate) struct BodyArgs<'a> {
+    pub args: [&'a str; 2], //reply-to前面固定的参数,比如subject和sid
+    pub reply_to: Option<&'a str>,
+    pub header_len: usize,
+    pub total_len: usize,
+}
+/**
+解析带消息体的控制行的参数,fixed是reply-to前面固定的参数个数:
+```text
+PUB <subject> [reply-to] <size>
+HPUB <subject> [reply-to] <header size> <total size>
+MSG <subject> <sid> [reply-to] <size>
+HMSG <subject> <sid> [reply-to] <header size> <total size>
+```
+*/
+pub(crate) fn parse_body_args(args: &str, fixed: usize, headers: bool) -> Result<BodyArgs<'_>> {
+    let sizes = if headers { 2 } else { 1 };
+    let mut buf = [""; 5];
+    let n = split_args(args, &mut buf[..fixed + 1 + sizes])?;
+    let reply_to = match n.checked_sub(fixed + sizes) {
+        Some(0) => None,
+        Some(1) => Some(buf[fixed]),
+        _ => parse_error!(),
+    };
+    let total_len = parse_size(buf[n - 1])?;
+    let header_len = if headers { parse_size(buf[n - 2])? } else { 0 };
+    if header_len > total_len {
+        parse_error!();
+    }
+    let mut fixed_args = [""; 2];
+    fixed_args[..fixed].copy_from_slice(&buf[..fixed]);
+    Ok(BodyArgs {
+        args: fixed_args,
+        reply_to,
+        header_len,
+        total_len,
+    })
+}
+/**
+从读缓冲区中切出一条长度为total的完整消息,不完整的时候什么都不消费,
+并且为这条消息预留好空间,下一次read直接读到后面,不用再拼接.
+*/
+pub(crate) fn split_frame(src: &mut BytesMut, total: Option<usize>) -> Option<Bytes> {
+    match total {
+        Some(total) if src.len() >= total => Some(src.split_to(total).freeze()),
+        Some(total) => {
+            src.reserve(total - src.len());
+            None
+        }
+        None => None,
+    }
+}
+//控制行后面的消息体,必须以\r\n结尾
+pub(crate) fn body(frame: &Bytes, line_len: usize) -> Result<Bytes> {
+    if frame.len() < line_len + 2 || &frame[frame.len() - 2..] != b"\r\n" {
+        parse_error!();
+    }
+    Ok(frame.slice(line_len..frame.len() - 2))
+}
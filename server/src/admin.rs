@@ -0,0 +1,102 @@
+/**
+### 本地的管理接口
+配置了`admin_addr`的时候监听这个地址,一般是127.0.0.1,一行一个命令,每个命令回复一行json:
+```text
+RELOAD
+{"changes":["max_payload: 1048576 -> 2048"]}
+RELOAD
+{"error":"listen address 0.0.0.0:4222 -> 0.0.0.0:4333 requires restart"}
+```
+命令不在这里执行,而是通过ServerHandle::admin_requests交给启动server的程序,
+重新加载配置文件和收到SIGHUP的时候是同一段代码,回复修改了哪些配置或者为什么失败.
+*/
+use futures::{select, FutureExt};
+use log::{debug, error};
+use serde_derive::Serialize;
+use std::error::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AdminCommand {
+    //重新加载配置
+    Reload,
+}
+#[derive(Debug)]
+pub struct AdminRequest {
+    pub command: AdminCommand,
+    reply: oneshot::Sender<AdminReply>,
+}
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum AdminReply {
+    Changes(Vec<String>),
+    Error(String),
+}
+impl AdminRequest {
+    //管理连接可能已经断开了,回复不出去也没关系
+    pub fn reply(self, r: &Result<Vec<String>, Box<dyn Error>>) {
+        let reply = match r {
+            Ok(changes) => AdminReply::Changes(changes.clone()),
+            Err(e) => AdminReply::Error(e.to_string()),
+        };
+        let _ = self.reply.send(reply);
+    }
+}
+//一直接受管理连接,直到stop被触发或者accept出错
+pub(crate) async fn serve(
+    mut listener: TcpListener,
+    tx: mpsc::Sender<AdminRequest>,
+    stop: oneshot::Receiver<()>,
+) {
+    let mut stop = stop.fuse();
+    loop {
+        let conn = select! {
+            r = listener.accept().fuse() => r,
+            _ = stop => return,
+        };
+        match conn {
+            Ok((conn, addr)) => {
+                debug!("admin connection from {}", addr);
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = process_connection(conn, tx).await {
+                        debug!("admin connection {} err {}", addr, e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("admin accept err {}", e);
+                return;
+            }
+        }
+    }
+}
+async fn process_connection(
+    conn: TcpStream,
+    mut tx: mpsc::Sender<AdminRequest>,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = tokio::io::split(conn);
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let command = line.trim();
+        let reply = match command.to_ascii_uppercase().as_str() {
+            "" => continue,
+            "RELOAD" => request(&mut tx, AdminCommand::Reload).await,
+            _ => AdminReply::Error(format!("unknown command {}", command)),
+        };
+        let mut buf = serde_json::to_vec(&reply)?;
+        buf.extend_from_slice(b"\r\n");
+        writer.write_all(&buf).await?;
+    }
+    Ok(())
+}
+async fn request(tx: &mut mpsc::Sender<AdminRequest>, command: AdminCommand) -> AdminReply {
+    let (reply, rx) = oneshot::channel();
+    if tx.send(AdminRequest { command, reply }).await.is_err() {
+        return AdminReply::Error("server is stopping".to_string());
+    }
+    rx.await
+        .unwrap_or_else(|_| AdminReply::Error("server is stopping".to_string()))
+}
@@ -1,48 +1,503 @@
 use crate::error::*;
-use crate::parser::{ParseResult, Parser, PubArg, SubArg};
+use crate::limits::{Limits, SharedLimits};
+use crate::logger::Logger;
+use crate::memory_budget::{MemoryBudget, Producer};
+use crate::queue_strategy::QueueSelector;
+use crate::rate_limit::{RateLimiter, SharedRateLimiter};
 use crate::server::*;
 use crate::simple_sublist::{ArcSubResult, ArcSubscription, SubListTrait, Subscription};
-use rand::{RngCore, SeedableRng};
-use std::collections::{BTreeSet, HashMap};
+use crate::sublist::{check_publish_subject, check_subject};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::future::Fuse;
+use futures::{select, FutureExt};
+use log::{debug, error, info, trace, warn};
+use lru_cache::LruCache;
+use protocol::{encode_msg, encode_msg_header, ClientOp, PubArg, ServerCodec, ServerOp, SubArg};
+pub use protocol::{ConnectInfo, ServerInfo};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::error::Error;
-use std::sync::Arc;
+use std::fmt;
+use std::io::IoSlice;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::io::*;
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::timeout;
+use tokio_util::codec::{Decoder, Encoder};
 
 #[derive(Debug)]
 pub struct Client<T: SubListTrait> {
     pub srv: Arc<Mutex<ServerState<T>>>,
+    pub sublist: Arc<RwLock<T>>,
     pub cid: u64,
-    pub msg_sender: Arc<Mutex<ClientMessageSender>>,
+    pub msg_sender: Arc<ClientMessageSender>,
+    pub connect_info: ConnectInfo,
+    //每读一批数据从shared_limits取一次快照,重新加载配置以后就按照新的限制来
+    pub limits: Arc<Limits>,
+    pub shared_limits: Arc<SharedLimits>,
+    pub addr: Option<SocketAddr>,
+    //发布的速率限制,CONNECT中指定了user的话还要受这个user的限制
+    pub rate_limiter: RateLimiter,
+    pub user_rate_limiter: Option<SharedRateLimiter>,
+    //payload不小于这个长度就在订阅者之间共享,正常都是SHARED_PAYLOAD_MIN,bench中用来比较两种做法
+    shared_payload_min: usize,
 }
-
+/**
+每个连接都有一个自己的写任务,其他连接的publisher只是把编码好的帧放到channel中,
+不会持有任何锁去等待socket,慢的订阅者也就不会拖住别人.
+publisher从来不等channel,写队列按照字节数限制:
+超过limits.max_pending_per_conn的一半,给它推消息的publisher暂停读,等它取走一些,
+还是超过了max_pending_per_conn,说明订阅者跟不上,直接断开这个慢消费者,
+否则所有给它推消息的publisher,以及这些publisher的其他订阅者都会被它拖住.
+每一帧都记在生产它的连接名下,所有连接加起来不能超过全局的内存预算,见memory_budget.
+*/
 #[derive(Debug)]
 pub struct ClientMessageSender {
-    writer: Option<WriteHalf<TcpStream>>,
-    msg_buf: Option<Vec<u8>>,
+    pub ctx: ConnContext,
+    tx: mpsc::UnboundedSender<Frame>,
+    //已经交给这个连接但是还没有写到socket的字节数
+    pending: AtomicUsize,
+    //pending超过这么多就作为慢消费者断开,0表示不限制,写任务每一批都从limits中更新
+    max_pending: AtomicUsize,
+    closed: AtomicBool,
+    //让写任务丢掉排队的帧马上退出,只能用一次
+    abort: std::sync::Mutex<Option<oneshot::Sender<NError>>>,
+    pub stats: ClientStats,
+    //这个连接作为生产者在内存预算中的份额
+    pub producer: Arc<Producer>,
+}
+//写任务那一端
+#[derive(Debug)]
+pub struct FrameReceiver {
+    pub frames: mpsc::UnboundedReceiver<Frame>,
+    abort: oneshot::Receiver<NError>,
+}
+//写队列中的一帧,写出去或者被丢掉的时候从producer名下扣掉
+#[derive(Debug)]
+pub struct Frame {
+    pub data: Bytes,
+    producer: Arc<Producer>,
+}
+//每个连接收发的统计,out是由推送消息的其他连接更新的
+#[derive(Debug, Default)]
+pub struct ClientStats {
+    pub in_msgs: AtomicU64,
+    pub in_bytes: AtomicU64,
+    pub out_msgs: AtomicU64,
+    pub out_bytes: AtomicU64,
+    pub subscriptions: AtomicUsize,
+    //因为超过速率限制暂停读的次数和总时间
+    pub throttles: AtomicU64,
+    pub throttled_us: AtomicU64,
+    //因为超过内存预算或者订阅者的写队列太长暂停读的次数和总时间
+    pub budget_pauses: AtomicU64,
+    pub budget_paused_us: AtomicU64,
+    //处理了太多消息主动让出的次数
+    pub yields: AtomicU64,
+}
+/**
+日志中的连接信息,输出的时候形如`cid=3 addr=127.0.0.1:50001`,
+和这个连接有关的日志都带着它
+*/
+#[derive(Debug, Default, Clone)]
+pub struct ConnContext {
+    pub cid: u64,
+    pub addr: Option<SocketAddr>,
+}
+impl fmt::Display for ConnContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.addr {
+            Some(addr) => write!(f, "cid={} addr={}", self.cid, addr),
+            None => write!(f, "cid={}", self.cid),
+        }
+    }
 }
 impl ClientMessageSender {
-    pub fn new(writer: WriteHalf<TcpStream>) -> Self {
-        Self {
-            writer: Some(writer),
-            msg_buf: Some(Vec::with_capacity(512)),
+    pub fn new(
+        ctx: ConnContext,
+        budget: Arc<MemoryBudget>,
+        max_pending: usize,
+    ) -> (Self, FrameReceiver) {
+        let (tx, frames) = mpsc::unbounded_channel();
+        let (abort_tx, abort) = oneshot::channel();
+        let sender = Self {
+            ctx,
+            tx,
+            pending: AtomicUsize::new(0),
+            max_pending: AtomicUsize::new(max_pending),
+            closed: AtomicBool::new(false),
+            abort: std::sync::Mutex::new(Some(abort_tx)),
+            stats: ClientStats::default(),
+            producer: Arc::new(Producer::new(budget)),
+        };
+        (sender, FrameReceiver { frames, abort })
+    }
+    //把一帧交给写任务,记在自己名下,调用者要先用add_pending记上这一帧的长度
+    pub fn send(&self, frame: Bytes) -> std::io::Result<()> {
+        self.send_from(frame, &self.producer)
+    }
+    //把其他连接发布的消息交给写任务,记在producer名下
+    pub fn send_from(&self, frame: Bytes, producer: &Arc<Producer>) -> std::io::Result<()> {
+        if self.is_closed() {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        let pending = self.pending.load(AtomicOrdering::Relaxed);
+        let max = self.max_pending.load(AtomicOrdering::Relaxed);
+        if max > 0 && pending > max {
+            warn!("{} slow consumer, {} bytes pending", self.ctx, pending);
+            self.abort(NError::new(ERROR_SLOW_CONSUMER));
+            return Err(ErrorKind::BrokenPipe.into());
         }
+        let n = frame.len();
+        producer.acquire(n);
+        let frame = Frame {
+            data: frame,
+            producer: producer.clone(),
+        };
+        //写任务已经退出了
+        self.tx.send(frame).map_err(|_| {
+            producer.release(n);
+            self.closed.store(true, AtomicOrdering::Relaxed);
+            std::io::Error::from(ErrorKind::BrokenPipe)
+        })
+    }
+    //不等这一批处理完,直接编码发送,比如INFO
+    pub fn send_op(&self, op: ServerOp) -> std::io::Result<()> {
+        let mut buf = BytesMut::new();
+        ServerCodec::default()
+            .encode(op, &mut buf)
+            .map_err(|e| std::io::Error::new(ErrorKind::Other, e))?;
+        self.add_pending(buf.len());
+        self.send(buf.freeze())
+    }
+    /**
+    通知写任务把已经排队的帧写完以后关闭连接,空的帧就是结束标志.
+    channel没有长度限制,只要写任务还在就一定能排上,写任务已经退出的话也就不需要通知了.
+    */
+    pub fn close(&self) {
+        if !self.closed.swap(true, AtomicOrdering::Relaxed) {
+            let _ = self.tx.send(Frame {
+                data: Bytes::new(),
+                producer: self.producer.clone(),
+            });
+        }
+    }
+    /**
+    不等排队的帧写完,让写任务马上关闭socket,排队的帧都丢掉.
+    读任务会从写任务那里收到reason,然后和其他断开的连接一样清理订阅.
+    */
+    pub fn abort(&self, reason: NError) {
+        self.closed.store(true, AtomicOrdering::Relaxed);
+        if let Some(abort) = self.abort.lock().unwrap().take() {
+            let _ = abort.send(reason);
+        }
+    }
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(AtomicOrdering::Relaxed)
+    }
+    //写队列超过了max_pending的一半,给它推消息的publisher应该暂停
+    pub fn is_congested(&self) -> bool {
+        let max = self.max_pending.load(AtomicOrdering::Relaxed);
+        max > 0 && !self.is_closed() && self.pending.load(AtomicOrdering::Relaxed) >= max / 2
+    }
+    //降到max_pending的1/4以下或者已经关闭,暂停的publisher可以恢复
+    pub fn is_drained(&self) -> bool {
+        let max = self.max_pending.load(AtomicOrdering::Relaxed);
+        max == 0 || self.is_closed() || self.pending.load(AtomicOrdering::Relaxed) <= max / 4
     }
-    async fn send_all(&mut self) -> std::io::Result<()> {
-        if let Some(ref mut writer) = self.writer {
-            let r = writer
-                .write_all(self.msg_buf.as_ref().unwrap().as_slice())
-                .await;
-            self.msg_buf.as_mut().unwrap().clear();
-            r
+    pub fn add_pending(&self, n: usize) {
+        self.pending.fetch_add(n, AtomicOrdering::Relaxed);
+    }
+    //还没有发送出去的字节数,连接已经关闭的认为无穷大
+    pub fn pending_bytes(&self) -> usize {
+        if self.is_closed() {
+            usize::max_value()
         } else {
-            Ok(())
+            self.pending.load(AtomicOrdering::Relaxed)
+        }
+    }
+}
+//ldm为true表示server进入了lame duck模式
+pub fn new_server_info(client_id: u64, max_payload: usize, ldm: bool) -> ServerInfo {
+    ServerInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        client_id,
+        max_payload,
+        ldm,
+    }
+}
+/**
+写任务从channel中取出已经排队的帧,一次writev写出去.
+收到空帧或者所有的sender都没了就退出,退出之前关闭socket.
+收到abort的话不管还有多少帧没写,马上退出,比如慢消费者.
+退出的原因(比如写出错或者超过deadline还写不出去)通过closed告诉读任务,由读任务清理订阅,
+被server踢掉的连接也是这样断开的.
+deadline和max_pending每批都从limits中重新取,重新加载配置以后马上生效.
+退出的时候还没写出去的帧都要从各自的producer名下扣掉,否则预算就漏了.
+*/
+async fn writer_task(
+    sender: Arc<ClientMessageSender>,
+    rx: FrameReceiver,
+    mut writer: WriteHalf<TcpStream>,
+    limits: Arc<SharedLimits>,
+    closed: oneshot::Sender<NError>,
+) {
+    let FrameReceiver {
+        frames: mut rx,
+        abort,
+    } = rx;
+    let mut abort = abort.fuse();
+    let mut batch = WriteBatch::default();
+    let mut quit = false;
+    let mut reason = None;
+    'outer: while !quit {
+        let frame = select! {
+            frame = rx.recv().fuse() => Ok(frame),
+            e = abort => Err(e),
+        };
+        match frame {
+            Ok(Some(frame)) if !frame.data.is_empty() => batch.push(frame),
+            Ok(_) => quit = true,
+            Err(e) => {
+                reason = Some(aborted(&sender.ctx, e));
+                break;
+            }
         }
+        //不等待,只合并已经在channel中的帧
+        while !quit && batch.frames.len() < MAX_WRITE_FRAMES {
+            match rx.recv().now_or_never() {
+                Some(Some(frame)) if !frame.data.is_empty() => batch.push(frame),
+                Some(_) => quit = true,
+                None => break,
+            }
+        }
+        let (deadline, max_pending) = {
+            let limits = limits.load();
+            (limits.write_deadline, limits.max_pending_per_conn)
+        };
+        sender
+            .max_pending
+            .store(max_pending, AtomicOrdering::Relaxed);
+        while batch.has_remaining() {
+            let r = select! {
+                r = timeout(deadline, writer.write_buf(&mut batch)).fuse() => r,
+                e = abort => {
+                    reason = Some(aborted(&sender.ctx, e));
+                    break 'outer;
+                }
+            };
+            match r {
+                Ok(Ok(n)) if n > 0 => {
+                    sender.pending.fetch_sub(n, AtomicOrdering::Relaxed);
+                }
+                Ok(r) => {
+                    debug!("{} write err {:?}", sender.ctx, r);
+                    reason = Some(NError::new(ERROR_CONNECTION_CLOSED));
+                    break 'outer;
+                }
+                //对方一直不读,接收窗口满了
+                Err(_) => {
+                    warn!("{} write deadline {:?} exceeded", sender.ctx, deadline);
+                    reason = Some(NError::new(ERROR_WRITE_DEADLINE_EXCEEDED));
+                    break 'outer;
+                }
+            }
+        }
+    }
+    sender.closed.store(true, AtomicOrdering::Relaxed);
+    batch.clear();
+    rx.close();
+    while let Some(frame) = rx.recv().await {
+        frame.producer.release(frame.data.len());
+    }
+    //读任务可能已经退出了
+    let _ = closed.send(reason.unwrap_or_else(|| NError::new(ERROR_CONNECTION_CLOSED)));
+    if let Err(e) = writer.shutdown().await {
+        debug!("{} shutdown err {:?}", sender.ctx, e);
+    }
+}
+fn aborted(
+    ctx: &ConnContext,
+    reason: std::result::Result<NError, oneshot::error::RecvError>,
+) -> NError {
+    let reason = reason.unwrap_or_else(|_| NError::new(ERROR_CONNECTION_CLOSED));
+    info!("{} aborted, {}", ctx, reason);
+    reason
+}
+//多个帧组成的Buf,这样tokio就可以用writev一次写出去
+//写出去的字节及时从producer名下扣掉
+#[derive(Debug, Default)]
+struct WriteBatch {
+    frames: VecDeque<Frame>,
+    remaining: usize,
+}
+impl WriteBatch {
+    fn push(&mut self, frame: Frame) {
+        self.remaining += frame.data.len();
+        self.frames.push_back(frame);
+    }
+    fn clear(&mut self) {
+        for frame in self.frames.drain(..) {
+            frame.producer.release(frame.data.len());
+        }
+        self.remaining = 0;
+    }
+}
+impl Buf for WriteBatch {
+    fn remaining(&self) -> usize {
+        self.remaining
+    }
+    fn bytes(&self) -> &[u8] {
+        self.frames.front().map(|f| f.data.as_ref()).unwrap_or(&[])
+    }
+    fn bytes_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+        let mut n = 0;
+        for (frame, slice) in self.frames.iter().zip(dst.iter_mut()) {
+            *slice = IoSlice::new(&frame.data);
+            n += 1;
+        }
+        n
+    }
+    fn advance(&mut self, mut cnt: usize) {
+        self.remaining -= cnt;
+        while cnt > 0 {
+            let front = self.frames.front_mut().unwrap();
+            if front.data.len() > cnt {
+                front.data.advance(cnt);
+                front.producer.release(cnt);
+                return;
+            }
+            cnt -= front.data.len();
+            front.producer.release(front.data.len());
+            self.frames.pop_front();
+        }
+    }
+}
+/**
+一批读出来的消息处理完以后才统一交给各个连接的写任务,
+同一个连接在这一批中的小消息合并成一帧,减少channel的开销.
+大的payload不拷贝,所有订阅者共享同一个Bytes,只有MSG控制行是各自的.
+所有的帧都记在producer,也就是发布消息的连接名下.
+*/
+#[derive(Debug, Default)]
+pub struct PendingFrames {
+    frames: BTreeMap<ClientMessageSenderWrapper, FrameBuf>,
+    producer: Arc<Producer>,
+    //flush以后写队列太长的订阅者,publisher要等它们取走一些再继续读
+    congested: Vec<Arc<ClientMessageSender>>,
+}
+#[derive(Debug, Default)]
+struct FrameBuf {
+    frames: Vec<Bytes>,
+    buf: BytesMut,
+}
+impl FrameBuf {
+    //共享的payload单独作为一帧,前面已经编码的内容先切出来
+    fn push_shared(&mut self, payload: Bytes) {
+        if !self.buf.is_empty() {
+            self.frames.push(self.buf.split().freeze());
+        }
+        self.frames.push(payload);
+    }
+}
+impl PendingFrames {
+    pub fn new(producer: Arc<Producer>) -> Self {
+        Self {
+            frames: BTreeMap::new(),
+            producer,
+            congested: Vec::new(),
+        }
+    }
+    fn frame_buf(&mut self, sender: &Arc<ClientMessageSender>) -> &mut FrameBuf {
+        let id = sender.as_ref() as *const ClientMessageSender as usize;
+        self.frames
+            .entry(ClientMessageSenderWrapper(sender.clone(), id))
+            .or_insert_with(FrameBuf::default)
+    }
+    fn buf(&mut self, sender: &Arc<ClientMessageSender>) -> &mut BytesMut {
+        &mut self.frame_buf(sender).buf
+    }
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+    //连接已经关闭的直接丢掉,不影响其他连接
+    fn flush(&mut self) {
+        let pendings = std::mem::take(&mut self.frames);
+        for (sender, mut frame_buf) in pendings {
+            if !frame_buf.buf.is_empty() {
+                frame_buf.frames.push(frame_buf.buf.freeze());
+            }
+            for frame in frame_buf.frames {
+                if let Err(e) = sender.0.send_from(frame, &self.producer) {
+                    debug!("{} send frame err {}", sender.0.ctx, e);
+                    break;
+                }
+            }
+            if sender.0.is_congested() {
+                self.congested.push(sender.0);
+            }
+        }
+    }
+    fn take_congested(&mut self) -> Vec<Arc<ClientMessageSender>> {
+        std::mem::take(&mut self.congested)
+    }
+}
+//每次从连接上至少读多少数据
+const READ_BUF_LEN: usize = 64 * 1024;
+//一次writev最多合并多少帧,和tokio一次writev的上限一致
+const MAX_WRITE_FRAMES: usize = 64;
+//payload超过这个长度就在订阅者之间共享,小的直接拷贝反而更快
+const SHARED_PAYLOAD_MIN: usize = 4 * 1024;
+//超过内存预算暂停读以后,每隔多久看一次是否可以恢复
+const BUDGET_POLL_INTERVAL: Duration = Duration::from_millis(5);
+//每个连接最多缓存多少个主题的查找结果
+const MATCH_CACHE_MAX: usize = 512;
+/**
+每个连接自己的查找缓存,只有client_task自己用,所以不用加锁.
+sublist每次insert/remove都会增加generation,发现generation变了就整个清空,
+这样先pub后sub的情况下,新的订阅也能收到后面的消息.
+*/
+#[derive(Debug)]
+pub struct MatchCache {
+    generation: u64,
+    cache: LruCache<String, ArcSubResult>,
+}
+impl MatchCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            generation: 0,
+            cache: LruCache::new(capacity),
+        }
+    }
+    pub fn match_subject<T: SubListTrait>(&mut self, sublist: &T, subject: &str) -> ArcSubResult {
+        let generation = sublist.generation();
+        if generation != self.generation {
+            self.cache.clear();
+            self.generation = generation;
+        }
+        if let Some(r) = self.cache.get_mut(subject) {
+            return Arc::clone(r);
+        }
+        let r = sublist.match_subject(subject);
+        self.cache.insert(subject.to_string(), Arc::clone(&r));
+        r
+    }
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+}
+impl Default for MatchCache {
+    fn default() -> Self {
+        Self::new(MATCH_CACHE_MAX)
     }
 }
 #[derive(Debug, Clone)]
-pub struct ClientMessageSenderWrapper(Arc<Mutex<ClientMessageSender>>, usize);
+pub struct ClientMessageSenderWrapper(Arc<ClientMessageSender>, usize);
 impl std::cmp::PartialEq for ClientMessageSenderWrapper {
     fn eq(&self, other: &Self) -> bool {
         self.cmp(other) == Ordering::Equal
@@ -62,166 +517,409 @@ impl std::cmp::Ord for ClientMessageSenderWrapper {
         self.1.cmp(&other.1)
     }
 }
-impl<T: SubListTrait + Send + 'static> Client<T> {
+impl<T: SubListTrait + Send + Sync + 'static> Client<T> {
     pub fn process_connection(
         cid: u64,
         srv: Arc<Mutex<ServerState<T>>>,
+        sublist: Arc<RwLock<T>>,
+        shared_limits: Arc<SharedLimits>,
+        budget: Arc<MemoryBudget>,
         conn: TcpStream,
-    ) -> Arc<Mutex<ClientMessageSender>> {
+    ) -> Arc<ClientMessageSender> {
+        let addr = conn.peer_addr().ok();
         let (reader, writer) = tokio::io::split(conn);
-        let msg_sender = Arc::new(Mutex::new(ClientMessageSender::new(writer)));
+        let ctx = ConnContext { cid, addr };
+        let max_pending = shared_limits.load().max_pending_per_conn;
+        let (msg_sender, rx) = ClientMessageSender::new(ctx.clone(), budget, max_pending);
+        let msg_sender = Arc::new(msg_sender);
+        let (closed_tx, closed_rx) = oneshot::channel();
+        tokio::spawn(writer_task(
+            msg_sender.clone(),
+            rx,
+            writer,
+            shared_limits.clone(),
+            closed_tx,
+        ));
+        let limits = shared_limits.load();
         let c = Client {
             srv: srv,
+            sublist,
             cid,
             msg_sender: msg_sender.clone(),
+            connect_info: Default::default(),
+            rate_limiter: RateLimiter::new(limits.rate_limit),
+            user_rate_limiter: None,
+            limits,
+            shared_limits,
+            addr,
+            shared_payload_min: SHARED_PAYLOAD_MIN,
         };
         tokio::spawn(async move {
-            Client::client_task(c, reader).await;
-            println!("client {}  client_task quit", cid);
+            Client::client_task(c, reader, closed_rx).await;
+            debug!("{} client_task quit", ctx);
         });
         msg_sender
     }
-    async fn client_task(self, mut reader: ReadHalf<TcpStream>) {
-        let mut parser = Parser::new();
+    async fn client_task(
+        mut self,
+        mut reader: ReadHalf<TcpStream>,
+        closed: oneshot::Receiver<NError>,
+    ) {
+        let mut codec = ServerCodec::new(self.limits.max_payload);
         let mut count: i32 = 0;
         let mut subs = HashMap::new();
-        let mut buf = [0; 1024 * 64];
-        let mut rng = rand::rngs::StdRng::from_entropy();
-        let mut cache = HashMap::new();
-        let mut pendings = BTreeSet::new();
+        self.register().await;
+        if let Err(e) = self.send_info().await {
+            self.process_error(e, subs).await;
+            return;
+        }
+        let mut buf = BytesMut::with_capacity(READ_BUF_LEN);
+        let mut selector = {
+            let strategies = self.srv.lock().await.queue_strategies.clone();
+            QueueSelector::new(strategies)
+        };
+        let mut cache = MatchCache::default();
+        let mut pendings = PendingFrames::new(self.msg_sender.producer.clone());
+        let mut closed = closed.fuse();
+        let mut budget = YieldBudget::default();
         loop {
-            //            let mut buf: Vec<u8> = Vec::new();
-            //            let r = tokio::io::copy(&mut reader, &mut buf).await;
-            //            match r {
-            //                Ok(r) => println!("recevied {} bytes", r),
-            //                Err(e) => println!("copy err: {}", e),
-            //            }
-            //            return;
             count += 1;
-            let r = reader.read(&mut buf[..]).await;
+            //split_frame已经为不完整的大消息预留了空间,这里只保证每次至少能读一批
+            if buf.capacity() - buf.len() < READ_BUF_LEN / 4 {
+                buf.reserve(READ_BUF_LEN);
+            }
+            let r = select! {
+                r = reader.read_buf(&mut buf).fuse() => r,
+                //写任务已经退出,连接不能用了
+                e = closed => {
+                    let e = e.unwrap_or_else(|_| NError::new(ERROR_CONNECTION_CLOSED));
+                    self.process_error(e, subs).await;
+                    return;
+                }
+            };
             if r.is_err() {
                 let e = r.unwrap_err();
                 self.process_error(e, subs).await;
                 return;
             }
-            let r = r.unwrap();
-            let n = r;
+            let n = r.unwrap();
             if n == 0 {
                 self.process_error(NError::new(ERROR_CONNECTION_CLOSED), subs)
                     .await;
                 return;
             }
-            //            pendings.clear();
-            let mut buf2 = &buf[0..n];
+            self.refresh_limits(&mut codec);
+            self.refresh_trace(&mut codec);
+            let stats = &self.msg_sender.stats;
+            let in_msgs = stats.in_msgs.load(AtomicOrdering::Relaxed);
+            let in_bytes = stats.in_bytes.load(AtomicOrdering::Relaxed);
             loop {
-                let r = parser.parse(&buf2[..]);
-                if r.is_err() {
-                    {
-                        let s = unsafe { std::str::from_utf8_unchecked(&buf2[..]) };
-                        println!("parse err buf={}", s);
+                let len = buf.len();
+                let op = match codec.decode(&mut buf) {
+                    Ok(Some(op)) => op,
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!(
+                            "{} parse err buf={}",
+                            self.msg_sender.ctx,
+                            String::from_utf8_lossy(&buf[..])
+                        );
+                        self.process_error(e, subs).await;
+                        return;
                     }
-                    self.process_error(r.unwrap_err(), subs).await;
-                    return;
-                }
-                let (result, left) = r.unwrap();
-
-                match result {
-                    ParseResult::NoMsg => {
-                        break;
-                    }
-                    ParseResult::Sub(ref sub) => {
+                };
+                match op {
+                    ClientOp::Connect(info) => self.process_connect(info).await,
+                    ClientOp::Sub(ref sub) => {
+                        //订阅失败只通知client,不断开连接
                         if let Err(e) = self.process_sub(sub, &mut subs).await {
+                            self.send_error(&e, &mut pendings).await;
+                        }
+                    }
+                    ClientOp::Unsub { sid, max_msgs } => {
+                        if let Err(e) = self.process_unsub(&sid, max_msgs, &mut subs) {
+                            self.send_error(&e, &mut pendings).await;
+                        }
+                    }
+                    ClientOp::Pub(ref pub_arg) => {
+                        if let Err(e) = self
+                            .process_pub(pub_arg, None, &mut cache, &mut selector, &mut pendings)
+                            .await
+                        {
                             self.process_error(e, subs).await;
                             return;
                         }
                     }
-                    ParseResult::Pub(ref pub_arg) => {
+                    ClientOp::Hpub {
+                        ref arg,
+                        ref headers,
+                    } => {
                         if let Err(e) = self
-                            .process_pub(pub_arg, &mut cache, &mut rng, &mut pendings)
+                            .process_pub(
+                                arg,
+                                Some(headers.as_ref()),
+                                &mut cache,
+                                &mut selector,
+                                &mut pendings,
+                            )
                             .await
                         {
                             self.process_error(e, subs).await;
                             return;
                         }
-                        parser.clear_msg_buf();
                     }
+                    ClientOp::Ping => self.send_op(ServerOp::Pong, &mut pendings).await,
+                    ClientOp::Pong => {}
                 }
-                if left == buf2.len() {
-                    break;
+                //一批数据中的命令很多的时候,处理一部分就先交给写任务,然后让出
+                if budget.consume(len - buf.len(), &self.limits) {
+                    pendings.flush();
+                    let _ = tokio::task::yield_now().await;
+                    self.msg_sender
+                        .stats
+                        .yields
+                        .fetch_add(1, AtomicOrdering::Relaxed);
+                }
+            }
+            //批量交给各个连接的写任务
+            pendings.flush();
+            //发得太快了就暂停读,publisher会被tcp的流控挡住,消息不会丢
+            let stats = &self.msg_sender.stats;
+            let msgs = stats.in_msgs.load(AtomicOrdering::Relaxed) - in_msgs;
+            let bytes = stats.in_bytes.load(AtomicOrdering::Relaxed) - in_bytes;
+            let delay = self.consume_rate(msgs, bytes);
+            if delay > Duration::from_secs(0) {
+                let stats = &self.msg_sender.stats;
+                stats.throttles.fetch_add(1, AtomicOrdering::Relaxed);
+                stats
+                    .throttled_us
+                    .fetch_add(delay.as_micros() as u64, AtomicOrdering::Relaxed);
+                if let Some(e) = pause(&mut closed, delay).await {
+                    self.process_error(e, subs).await;
+                    return;
                 }
-                buf2 = &buf2[left..];
             }
-            //批量处理发送
-            for c in pendings.iter() {
-                let c = c.clone();
-                tokio::spawn(async move {
-                    let mut sender = c.0.lock().await;
-                    if let Err(e) = sender.send_all().await {
-                        println!("send_all error {}", e);
+            //超过了全局的内存预算,名下待发送字节多的publisher暂停读,等订阅者把数据取走,
+            //推过消息的订阅者写队列太长的话也要等它取走一些,否则它很快就会作为慢消费者断开
+            let producer = self.msg_sender.producer.clone();
+            let over_budget = producer.should_pause();
+            let congested = pendings.take_congested();
+            if over_budget || !congested.is_empty() {
+                let start = Instant::now();
+                debug!(
+                    "{} paused, {} bytes pending, {} congested subscribers",
+                    self.msg_sender.ctx,
+                    producer.pending(),
+                    congested.len()
+                );
+                while (over_budget && !producer.should_resume())
+                    || congested.iter().any(|s| !s.is_drained())
+                {
+                    if let Some(e) = pause(&mut closed, BUDGET_POLL_INTERVAL).await {
+                        self.process_error(e, subs).await;
+                        return;
                     }
-                });
+                }
+                let stats = &self.msg_sender.stats;
+                stats.budget_pauses.fetch_add(1, AtomicOrdering::Relaxed);
+                stats
+                    .budget_paused_us
+                    .fetch_add(start.elapsed().as_micros() as u64, AtomicOrdering::Relaxed);
             }
-            pendings.clear();
+        }
+    }
+    //同一个user的连接共享一个限速器
+    async fn process_connect(&mut self, info: ConnectInfo) {
+        let mut srv = self.srv.lock().await;
+        if let Some(entry) = srv.clients.get_mut(&self.cid) {
+            entry.name = info.name.clone();
+        }
+        self.user_rate_limiter = info
+            .user
+            .as_ref()
+            .map(|user| srv.user_rate_limiter(user));
+        drop(srv);
+        self.connect_info = info;
+    }
+    //这一批发布的消息扣掉连接和user的令牌,返回需要暂停多久
+    fn consume_rate(&mut self, msgs: u64, bytes: u64) -> Duration {
+        if msgs == 0 {
+            return Duration::from_secs(0);
+        }
+        let delay = self.rate_limiter.consume(msgs, bytes);
+        match self.user_rate_limiter {
+            Some(ref l) => delay.max(l.lock().unwrap().consume(msgs, bytes)),
+            None => delay,
         }
     }
     async fn process_error<E: Error>(&self, err: E, subs: HashMap<String, ArcSubscription>) {
-        println!("client {} process err {:?}", self.cid, err);
-        {
-            let sublist = &mut self.srv.lock().await.sublist;
-            for (_, sub) in subs {
-                if let Err(e) = sublist.remove(sub) {
-                    println!("client {} remove err {} ", self.cid, e);
-                }
-            }
+        info!("{} closed, {}", self.msg_sender.ctx, err);
+        self.srv.lock().await.clients.remove(&self.cid);
+        self.remove_subs(subs);
+        self.msg_sender.close();
+    }
+    //配置重新加载过的话换成新的限制,新的max_payload由server通过INFO告诉client
+    fn refresh_limits(&mut self, codec: &mut ServerCodec) {
+        let limits = self.shared_limits.load();
+        if !Arc::ptr_eq(&limits, &self.limits) {
+            codec.set_max_payload(limits.max_payload);
+            self.rate_limiter.set_limit(limits.rate_limit);
+            self.limits = limits;
+        }
+    }
+    //--trace可以在运行中打开和关闭
+    fn refresh_trace(&self, codec: &mut ServerCodec) {
+        let trace = Logger::is_trace();
+        if trace != codec.is_trace() {
+            codec.set_trace(if trace {
+                Some(self.msg_sender.ctx.to_string())
+            } else {
+                None
+            });
         }
-        let mut sender = self.msg_sender.lock().await;
-        if let Some(mut writer) = sender.writer.take() {
-            sender.msg_buf.take();
-            if let Err(e) = writer.shutdown().await {
-                println!("shutdown err {:?}", e);
+    }
+    //登记到server中,这样server才能列出和踢掉这个连接
+    async fn register(&self) {
+        let entry = ClientEntry {
+            addr: self.addr,
+            start: SystemTime::now(),
+            name: None,
+            msg_sender: self.msg_sender.clone(),
+        };
+        self.srv.lock().await.clients.insert(self.cid, entry);
+        info!("{} accepted", self.msg_sender.ctx);
+    }
+    ///连接建立以后首先发送INFO
+    ///```text
+    /// INFO {"version":"0.1.0","client_id":1,"max_payload":1048576}\r\n
+    /// ```
+    async fn send_info(&self) -> std::io::Result<()> {
+        let info = new_server_info(self.cid, self.limits.max_payload, false);
+        self.msg_sender.send_op(ServerOp::Info(info))
+    }
+    //连接断开的时候,这个连接上的所有订阅都要从sublist中删掉
+    fn remove_subs(&self, subs: HashMap<String, ArcSubscription>) {
+        let mut sublist = self.sublist.write().unwrap();
+        for (_, sub) in subs {
+            if let Err(e) = sublist.remove(sub) {
+                error!("{} remove sub err {}", self.msg_sender.ctx, e);
             }
         }
     }
+    //echo为false时,不给自己的订阅推送自己发布的消息
+    fn is_echo_suppressed(&self, sub: &Subscription) -> bool {
+        !self.connect_info.echo && Arc::ptr_eq(&sub.msg_sender, &self.msg_sender)
+    }
     async fn process_sub(
         &self,
-        sub: &SubArg<'_>,
+        sub: &SubArg,
         subs: &mut HashMap<String, ArcSubscription>,
     ) -> crate::error::Result<()> {
         let sub = Subscription {
-            subject: sub.subject.to_string(),
-            queue: sub.queue.map(|q| q.to_string()),
-            sid: sub.sid.to_string(),
+            subject: sub.subject.clone(),
+            queue: sub.queue.clone(),
+            sid: sub.sid.clone(),
             msg_sender: self.msg_sender.clone(),
         };
+        check_subject(sub.subject.as_str(), &self.limits)?;
+        //同一个连接上sid不能重复,否则后面的订阅就会覆盖前面的
+        if subs.contains_key(sub.sid.as_str()) {
+            return Err(NError::new(ERROR_DUPLICATE_SID));
+        }
+        if subs.len() >= self.limits.max_subs_per_conn {
+            return Err(NError::new(ERROR_MAX_SUBSCRIPTIONS_PER_CONNECTION));
+        }
         let sub = Arc::new(sub);
-        subs.insert(sub.subject.clone(), sub.clone());
-        let sublist = &mut self.srv.lock().await.sublist;
-        sublist.insert(sub)?;
+        {
+            let mut sublist = self.sublist.write().unwrap();
+            if sublist.count() >= self.limits.max_total_subs {
+                return Err(NError::new(ERROR_MAX_SUBSCRIPTIONS));
+            }
+            sublist.insert(sub.clone())?;
+        }
+        subs.insert(sub.sid.clone(), sub);
+        self.msg_sender
+            .stats
+            .subscriptions
+            .store(subs.len(), AtomicOrdering::Relaxed);
         Ok(())
     }
+    /**
+    UNSUB <sid> [max_msgs]
+    还不支持收到max_msgs条消息以后自动取消订阅
+    */
+    fn process_unsub(
+        &self,
+        sid: &str,
+        max_msgs: Option<u64>,
+        subs: &mut HashMap<String, ArcSubscription>,
+    ) -> crate::error::Result<()> {
+        if max_msgs.is_some() {
+            return Err(NError::new(ERROR_AUTO_UNSUBSCRIBE_NOT_SUPPORTED));
+        }
+        let sub = subs
+            .remove(sid)
+            .ok_or_else(|| NError::new(ERROR_SUBSCRIBTION_NOT_FOUND))?;
+        self.msg_sender
+            .stats
+            .subscriptions
+            .store(subs.len(), AtomicOrdering::Relaxed);
+        self.sublist.write().unwrap().remove(sub)
+    }
+    ///错误格式
+    ///```text
+    /// -ERR '<error description>'\r\n
+    /// ```
+    async fn send_error(&self, err: &NError, pendings: &mut PendingFrames) {
+        debug!("{} send err {}", self.msg_sender.ctx, err);
+        self.send_op(ServerOp::Err(err.error_description().to_string()), pendings)
+            .await;
+    }
+    //给自己发送INFO,PONG,-ERR这些不是消息的回复
+    async fn send_op(&self, op: ServerOp, pendings: &mut PendingFrames) {
+        if self.msg_sender.is_closed() {
+            return;
+        }
+        let buf = pendings.buf(&self.msg_sender);
+        let start = buf.len();
+        if let Err(e) = ServerCodec::default().encode(op, buf) {
+            error!("{} encode err {}", self.msg_sender.ctx, e);
+            return;
+        }
+        self.msg_sender.add_pending(buf.len() - start);
+    }
     async fn process_pub(
         &self,
-        pub_arg: &PubArg<'_>,
-        cache: &mut HashMap<String, ArcSubResult>,
-        rng: &mut rand::rngs::StdRng,
-        pendings: &mut BTreeSet<ClientMessageSenderWrapper>,
+        pub_arg: &PubArg,
+        headers: Option<&[u8]>,
+        cache: &mut MatchCache,
+        selector: &mut QueueSelector,
+        pendings: &mut PendingFrames,
     ) -> crate::error::Result<()> {
+        //主题不对只通知client,不断开连接,也不去sublist中查找
+        if let Err(e) = check_publish_subject(pub_arg.subject.as_str(), &self.limits) {
+            self.send_error(&e, pendings).await;
+            return Ok(());
+        }
+        let size = pub_arg.payload.len() + headers.map(|h| h.len()).unwrap_or(0);
+        let stats = &self.msg_sender.stats;
+        stats.in_msgs.fetch_add(1, AtomicOrdering::Relaxed);
+        stats
+            .in_bytes
+            .fetch_add(size as u64, AtomicOrdering::Relaxed);
         let sub_result = {
-            if let Some(r) = cache.get(pub_arg.subject) {
-                Arc::clone(r)
-            } else {
-                let sub_list = &mut self.srv.lock().await.sublist;
-                let r = sub_list.match_subject(pub_arg.subject);
-                cache.insert(pub_arg.subject.to_string(), Arc::clone(&r));
-                r
-            }
+            let sublist = self.sublist.read().unwrap();
+            cache.match_subject(&*sublist, pub_arg.subject.as_str())
         };
         if sub_result.psubs.len() > 0 {
             for sub in sub_result.psubs.iter() {
-                self.send_message(sub.as_ref(), pub_arg, pendings)
+                if self.is_echo_suppressed(sub) {
+                    continue;
+                }
+                self.send_message(sub.as_ref(), pub_arg, headers, pendings)
                     .await
                     .map_err(|e| {
-                        println!("send message error {}", e);
+                        debug!("{} send message err {}", self.msg_sender.ctx, e);
                         NError::new(ERROR_CONNECTION_CLOSED)
                     })?;
             }
@@ -229,113 +927,233 @@ impl<T: SubListTrait + Send + 'static> Client<T> {
         if sub_result.qsubs.len() > 0 {
             //qsubs 要考虑负载均衡问题
             for qsubs in sub_result.qsubs.iter() {
-                let n = rng.next_u32();
-                let n = n as usize % qsubs.len();
-                let sub = qsubs.get(n).unwrap();
-                self.send_message(sub.as_ref(), pub_arg, pendings)
+                let candidates: Vec<_> = qsubs
+                    .iter()
+                    .filter(|sub| !self.is_echo_suppressed(sub))
+                    .collect();
+                if candidates.is_empty() {
+                    continue;
+                }
+                let n = selector.select(candidates.as_slice(), pub_arg.subject.as_str());
+                let sub = candidates[n];
+                self.send_message(sub.as_ref(), pub_arg, headers, pendings)
                     .await
                     .map_err(|_| NError::new(ERROR_CONNECTION_CLOSED))?;
             }
         }
         Ok(())
     }
-    ///消息格式
-    ///```
-    /// MSG <subject> <sid> <size>\r\n
+    ///消息格式,subject是publisher发布的主题,不是订阅的主题,client要按照sid区分订阅
+    ///```text
+    /// MSG <subject> <sid> [reply-to] <size>\r\n
     /// <message>\r\n
     /// ```
     async fn send_message(
         &self,
         sub: &Subscription,
-        pub_arg: &PubArg<'_>,
-        pendings: &mut BTreeSet<ClientMessageSenderWrapper>,
+        pub_arg: &PubArg,
+        headers: Option<&[u8]>,
+        pendings: &mut PendingFrames,
     ) -> std::io::Result<()> {
-        let mut msg_sender = sub.msg_sender.lock().await;
-        if let Some(ref mut msg_buf) = msg_sender.msg_buf {
-            let id = msg_sender.deref() as *const ClientMessageSender as usize;
-            let msg_buf = msg_sender.msg_buf.as_mut().unwrap();
-
-            msg_buf.extend_from_slice("MSG ".as_bytes());
-            msg_buf.extend_from_slice(sub.subject.as_bytes());
-            msg_buf.extend_from_slice(" ".as_bytes());
-            msg_buf.extend_from_slice(sub.sid.as_bytes());
-            msg_buf.extend_from_slice(" ".as_bytes());
-            msg_buf.extend_from_slice(pub_arg.size_buf.as_bytes());
-            msg_buf.extend_from_slice("\r\n".as_bytes());
-            msg_buf.extend_from_slice(pub_arg.msg); //经测试,如果这里不使用缓存,而是多个await,性能会大幅下降.
-            msg_buf.extend_from_slice("\r\n".as_bytes());
-            pendings.insert(ClientMessageSenderWrapper(sub.msg_sender.clone(), id));
+        //已经断开的订阅者还没来得及从sublist中删除,直接跳过
+        if sub.msg_sender.is_closed() {
+            return Ok(());
+        }
+        let size = pub_arg.payload.len() + headers.map(|h| h.len()).unwrap_or(0);
+        let stats = &sub.msg_sender.stats;
+        stats.out_msgs.fetch_add(1, AtomicOrdering::Relaxed);
+        stats
+            .out_bytes
+            .fetch_add(size as u64, AtomicOrdering::Relaxed);
+        let frame_buf = pendings.frame_buf(&sub.msg_sender);
+        let start = frame_buf.buf.len();
+        if pub_arg.payload.len() < self.shared_payload_min {
+            encode_msg(
+                &mut frame_buf.buf,
+                pub_arg.subject.as_str(),
+                sub.sid.as_str(),
+                pub_arg.reply_to.as_deref(),
+                headers,
+                &pub_arg.payload,
+            );
+            if Logger::is_trace() {
+                trace_msg(&sub.msg_sender.ctx, &frame_buf.buf[start..]);
+            }
+            sub.msg_sender.add_pending(frame_buf.buf.len() - start);
+            return Ok(());
         }
+        encode_msg_header(
+            &mut frame_buf.buf,
+            pub_arg.subject.as_str(),
+            sub.sid.as_str(),
+            pub_arg.reply_to.as_deref(),
+            headers,
+            pub_arg.payload.len(),
+        );
+        let header_len = frame_buf.buf.len() - start;
+        if Logger::is_trace() {
+            trace_msg(&sub.msg_sender.ctx, &frame_buf.buf[start..]);
+        }
+        frame_buf.push_shared(pub_arg.payload.clone());
+        frame_buf.buf.extend_from_slice(b"\r\n");
+        sub.msg_sender
+            .add_pending(header_len + pub_arg.payload.len() + 2);
         Ok(())
     }
-    /* async fn send_message2(sub: Arc<Subscription>, msg: Arc<Vec<u8>>) -> std::io::Result<()> {
-        let mut msg_sender = sub.msg_sender.lock().await;
-        let msg_buf = msg_sender.msg_buf.take().expect("must have");
-        let writer = &mut msg_sender.writer;
-        let mut buf = msg_buf.writer();
-        buf.write("MSG ".as_bytes())?;
-        buf.write(sub.subject.as_bytes())?;
-        buf.write(" ".as_bytes())?;
-        buf.write(sub.sid.as_bytes())?;
-        buf.write(" ".as_bytes())?;
-        write!(buf, "{}", msg.len())?;
-        buf.write("\r\n".as_bytes())?;
-        buf.write(msg.as_slice())?; //经测试,如果这里不使用缓存,而是多个await,性能会大幅下降.
-        buf.write("\r\n".as_bytes())?;
-        let mut msg_buf = buf.into_inner();
-        writer.write_all(msg_buf.bytes()).await?;
-        //        writer.flush().await?; 暂不需要flush,因为没有使用BufWriter
-        msg_buf.clear();
-        msg_sender.msg_buf = Some(msg_buf);
-        Ok(())
-    }*/
+}
+/**
+client_task一次读出来的数据可能有几千条PUB,每条还要推给很多订阅者,
+全部处理完才await的话,同一个worker上的其他连接都要等着,低频的publisher延迟就很高.
+连续处理了limits.yield_msgs条命令或者limits.yield_bytes字节以后就主动让出一次.
+*/
+#[derive(Debug, Default)]
+struct YieldBudget {
+    msgs: usize,
+    bytes: usize,
+}
+impl YieldBudget {
+    //记上处理完的一条命令,返回是否应该让出
+    fn consume(&mut self, bytes: usize, limits: &Limits) -> bool {
+        self.msgs += 1;
+        self.bytes += bytes;
+        let by_msgs = limits.yield_msgs > 0 && self.msgs >= limits.yield_msgs;
+        let by_bytes = limits.yield_bytes > 0 && self.bytes >= limits.yield_bytes;
+        if by_msgs || by_bytes {
+            *self = Self::default();
+            return true;
+        }
+        false
+    }
+}
+//暂停读socket,期间写任务退出的话返回原因
+async fn pause(
+    mut closed: &mut Fuse<oneshot::Receiver<NError>>,
+    delay: Duration,
+) -> Option<NError> {
+    select! {
+        _ = tokio::time::delay_for(delay).fuse() => None,
+        e = closed => Some(e.unwrap_or_else(|_| NError::new(ERROR_CONNECTION_CLOSED))),
+    }
+}
+//--trace的时候打印发给订阅者的MSG控制行,不包括消息体
+fn trace_msg(ctx: &ConnContext, encoded: &[u8]) {
+    let end = encoded
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .unwrap_or(encoded.len());
+    trace!("{} -> {}", ctx, String::from_utf8_lossy(&encoded[..end]));
 }
 #[cfg(test)]
 pub mod test_helper {
     use super::*;
     use lazy_static::lazy_static;
     lazy_static! {
-        static ref SENDER: Arc<Mutex<ClientMessageSender>> = {
-           let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
-        //    let port = l.local_addr().unwrap().port();
-        let conn = std::net::TcpStream::connect(l.local_addr().unwrap()).unwrap();
-        let (tx, rx) = std::sync::mpsc::channel();
-
-        let rt: tokio::runtime::Runtime = tokio::runtime::Builder::new()
-            .threaded_scheduler()
-            .enable_all()
-            .build()
-            .unwrap();
-
-        rt.spawn(async move {
-            println!("tokio spawned");
-            let conn = tokio::net::TcpStream::from_std(conn).unwrap();
-            let (_, writer) = tokio::io::split(conn);
-            println!("send start");
-            let _=tx.send(writer);
-            println!("send complete")
-        });
-        let writer = rx.recv().unwrap();
-          Arc::new(Mutex::new(ClientMessageSender::new(writer)))
-        };
+        //只用来区分订阅者,所有测试共用一个
+        static ref SENDER: Arc<ClientMessageSender> = new_test_sender();
     }
     #[cfg(test)]
-    pub fn new_test_tcp_writer() -> Arc<Mutex<ClientMessageSender>> {
+    pub fn new_test_tcp_writer() -> Arc<ClientMessageSender> {
         SENDER.clone()
     }
+    //没有写任务的sender,发送的帧都留在channel中,用received取出来检查
+    #[cfg(test)]
+    pub fn new_test_channel() -> (Arc<ClientMessageSender>, FrameReceiver) {
+        let (sender, rx) = ClientMessageSender::new(
+            Default::default(),
+            Default::default(),
+            Limits::default().max_pending_per_conn,
+        );
+        (Arc::new(sender), rx)
+    }
+    #[cfg(test)]
+    pub fn new_test_sender() -> Arc<ClientMessageSender> {
+        new_test_channel().0
+    }
+    //取出channel中所有已经发送的帧,和写任务一样从生产者名下扣掉
+    #[cfg(test)]
+    pub fn received(rx: &mut FrameReceiver) -> Vec<u8> {
+        let mut buf = Vec::new();
+        while let Some(Some(frame)) = rx.frames.recv().now_or_never() {
+            frame.producer.release(frame.data.len());
+            buf.extend_from_slice(&frame.data);
+        }
+        buf
+    }
+    #[cfg(test)]
+    pub async fn new_test_client<T: SubListTrait>(
+        srv: Arc<Mutex<ServerState<T>>>,
+        cid: u64,
+    ) -> (Client<T>, FrameReceiver) {
+        let (sublist, shared_limits) = {
+            let srv = srv.lock().await;
+            (srv.sublist.clone(), srv.limits.clone())
+        };
+        let (msg_sender, rx) = new_test_channel();
+        let limits = shared_limits.load();
+        let c = Client {
+            srv,
+            sublist,
+            cid,
+            msg_sender,
+            connect_info: Default::default(),
+            rate_limiter: RateLimiter::new(limits.rate_limit),
+            user_rate_limiter: None,
+            limits,
+            shared_limits,
+            addr: None,
+            shared_payload_min: SHARED_PAYLOAD_MIN,
+        };
+        (c, rx)
+    }
+    //模拟还有n个字节没有发送出去
+    #[cfg(test)]
+    pub fn fill_test_sender(sender: &ClientMessageSender, n: usize) {
+        sender.add_pending(n);
+    }
 }
 use std::cmp::Ordering;
-use std::ops::Deref;
 #[cfg(test)]
 pub use test_helper::new_test_tcp_writer;
+#[cfg(test)]
+pub use test_helper::{
+    fill_test_sender, new_test_channel, new_test_client, new_test_sender, received,
+};
 
 #[cfg(test)]
 mod tests {
     use super::*;
     extern crate test;
+    use crate::sublist::TrieSubList;
+    use bytes::Bytes;
+    use rand::{RngCore, SeedableRng};
     use std::io::Write;
     use test::Bencher;
 
+    fn new_test_srv() -> Arc<Mutex<ServerState<TrieSubList>>> {
+        Default::default()
+    }
+    //本机上连好的一对socket,第一个是client那一端,第二个是server accept到的
+    async fn new_test_conn() -> (TcpStream, TcpStream) {
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = TcpStream::connect(addr).await.unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+        (peer, conn)
+    }
+    //按srv中的sublist和limits处理一个新连接,返回client那一端和连接的sender
+    async fn start_test_connection(
+        srv: &Arc<Mutex<ServerState<TrieSubList>>>,
+    ) -> (TcpStream, Arc<ClientMessageSender>) {
+        let (sublist, limits) = {
+            let srv = srv.lock().await;
+            (srv.sublist.clone(), srv.limits.clone())
+        };
+        let (peer, conn) = new_test_conn().await;
+        let sender =
+            Client::process_connection(1, srv.clone(), sublist, limits, Default::default(), conn);
+        (peer, sender)
+    }
+
     #[test]
     fn test() {}
     #[test]
@@ -355,6 +1173,611 @@ mod tests {
         assert_eq!(buf.capacity(), 100);
         assert_eq!(buf.len(), 0);
     }
+    #[test]
+    fn test_connect_info() {
+        let info: ConnectInfo = serde_json::from_str("{}").unwrap();
+        assert!(info.echo);
+        let info: ConnectInfo =
+            serde_json::from_str("{\"echo\":false,\"name\":\"chat\",\"lang\":\"rust\"}").unwrap();
+        assert!(!info.echo);
+        assert_eq!(info.name.as_ref().unwrap(), "chat");
+    }
+    #[tokio::test]
+    async fn test_no_echo() {
+        let srv = new_test_srv();
+        let (mut c, _rx) = new_test_client(srv.clone(), 1).await;
+        let (other, _other_rx) = new_test_channel();
+        {
+            let mut sublist = c.sublist.write().unwrap();
+            let own = Subscription::new("chat", None, "1", c.msg_sender.clone());
+            sublist.insert(Arc::new(own)).unwrap();
+            let sub = Subscription::new("chat", None, "1", other.clone());
+            sublist.insert(Arc::new(sub)).unwrap();
+            let own = Subscription::new("chat", Some("q"), "2", c.msg_sender.clone());
+            sublist.insert(Arc::new(own)).unwrap();
+        }
+        let pub_arg = PubArg {
+            subject: "chat".to_string(),
+            reply_to: None,
+            payload: Bytes::from_static(b"hello"),
+        };
+        let mut cache = MatchCache::default();
+        let mut selector = QueueSelector::new(Default::default());
+        let mut pendings = PendingFrames::default();
+        c.process_pub(&pub_arg, None, &mut cache, &mut selector, &mut pendings)
+            .await
+            .unwrap();
+        assert!(c.msg_sender.pending_bytes() > 0);
+        assert!(other.pending_bytes() > 0);
+
+        c.connect_info = serde_json::from_str("{\"echo\":false}").unwrap();
+        let own_len = c.msg_sender.pending_bytes();
+        let other_len = other.pending_bytes();
+        c.process_pub(&pub_arg, None, &mut cache, &mut selector, &mut pendings)
+            .await
+            .unwrap();
+        assert_eq!(c.msg_sender.pending_bytes(), own_len);
+        assert_eq!(other.pending_bytes(), other_len * 2);
+    }
+    #[test]
+    fn test_match_cache_bounded() {
+        let sublist = TrieSubList::new();
+        let mut cache = MatchCache::new(4);
+        for i in 0..10 {
+            let r = cache.match_subject(&sublist, format!("foo.{}", i).as_str());
+            assert_eq!(r.psubs.len(), 0);
+        }
+        assert_eq!(cache.len(), 4);
+    }
+    //先pub后sub,之前缓存的结果不能影响新的订阅
+    #[tokio::test]
+    async fn test_sub_after_pub() {
+        let srv = new_test_srv();
+        let (c, _rx) = new_test_client(srv.clone(), 1).await;
+        let pub_arg = PubArg {
+            subject: "foo".to_string(),
+            reply_to: None,
+            payload: Bytes::from_static(b"hello"),
+        };
+        let mut cache = MatchCache::default();
+        let mut selector = QueueSelector::new(Default::default());
+        let mut pendings = PendingFrames::default();
+        c.process_pub(&pub_arg, None, &mut cache, &mut selector, &mut pendings)
+            .await
+            .unwrap();
+        assert_eq!(cache.len(), 1);
+        let receiver = new_test_sender();
+        let sub = Subscription::new("foo", None, "1", receiver.clone());
+        c.sublist.write().unwrap().insert(Arc::new(sub)).unwrap();
+        c.process_pub(&pub_arg, None, &mut cache, &mut selector, &mut pendings)
+            .await
+            .unwrap();
+        assert!(receiver.pending_bytes() > 0);
+    }
+    #[tokio::test]
+    async fn test_sub_limits() {
+        let srv = new_test_srv();
+        srv.lock().await.limits.store(Limits {
+            max_subject_tokens: 3,
+            max_subs_per_conn: 2,
+            max_total_subs: 3,
+            ..Default::default()
+        });
+        let (c1, mut rx1) = new_test_client(srv.clone(), 1).await;
+        let (c2, _rx2) = new_test_client(srv.clone(), 2).await;
+        let mut subs1 = HashMap::new();
+        let mut subs2 = HashMap::new();
+        let sub_arg = |subject: &str, sid: &str| SubArg {
+            subject: subject.to_string(),
+            sid: sid.to_string(),
+            queue: None,
+        };
+        let code = |r: crate::error::Result<()>| r.unwrap_err().err_code;
+        assert_eq!(
+            code(c1.process_sub(&sub_arg("a.b.c.d", "1"), &mut subs1).await),
+            ERROR_SUBJECT_TOO_MANY_TOKENS
+        );
+        assert_eq!(
+            code(c1.process_sub(&sub_arg("a..b", "1"), &mut subs1).await),
+            ERROR_INVALID_SUBJECT
+        );
+        c1.process_sub(&sub_arg("a", "1"), &mut subs1)
+            .await
+            .unwrap();
+        c1.process_sub(&sub_arg("b", "2"), &mut subs1)
+            .await
+            .unwrap();
+        assert_eq!(
+            code(c1.process_sub(&sub_arg("c", "3"), &mut subs1).await),
+            ERROR_MAX_SUBSCRIPTIONS_PER_CONNECTION
+        );
+        c2.process_sub(&sub_arg("c", "1"), &mut subs2)
+            .await
+            .unwrap();
+        assert_eq!(
+            code(c2.process_sub(&sub_arg("d", "2"), &mut subs2).await),
+            ERROR_MAX_SUBSCRIPTIONS
+        );
+        //失败的订阅不会留在sublist中
+        assert_eq!(c1.sublist.read().unwrap().count(), 3);
+        assert_eq!(subs1.len(), 2);
+        assert_eq!(subs2.len(), 1);
+
+        let mut pendings = PendingFrames::default();
+        c1.send_error(&NError::new(ERROR_MAX_SUBSCRIPTIONS), &mut pendings)
+            .await;
+        assert_eq!(pendings.len(), 1);
+        pendings.flush();
+        assert_eq!(
+            received(&mut rx1).as_slice(),
+            "-ERR 'maximum subscriptions exceeded'\r\n".as_bytes()
+        );
+    }
+    //同一个主题订阅多次,或者在不同的queue中订阅,断开以后sublist中都不能有残留
+    #[tokio::test]
+    async fn test_remove_overlapping_subs() {
+        let srv = new_test_srv();
+        let (c, _rx) = new_test_client(srv.clone(), 1).await;
+        let mut subs = HashMap::new();
+        let args = [
+            ("foo", None, "1"),
+            ("foo", None, "2"),
+            ("foo", Some("q1"), "3"),
+            ("foo", Some("q2"), "4"),
+            ("foo.*", None, "5"),
+            ("foo.>", Some("q1"), "6"),
+        ];
+        for (subject, queue, sid) in args.iter() {
+            let arg = SubArg {
+                subject: subject.to_string(),
+                queue: queue.map(|q| q.to_string()),
+                sid: sid.to_string(),
+            };
+            c.process_sub(&arg, &mut subs).await.unwrap();
+        }
+        let dup = SubArg {
+            subject: "bar".to_string(),
+            queue: None,
+            sid: "1".to_string(),
+        };
+        assert_eq!(
+            c.process_sub(&dup, &mut subs).await.unwrap_err().err_code,
+            ERROR_DUPLICATE_SID
+        );
+        assert_eq!(subs.len(), args.len());
+        assert_eq!(c.sublist.read().unwrap().count(), args.len());
+        let r = c.sublist.read().unwrap().match_subject("foo");
+        assert_eq!(r.psubs.len(), 2);
+        assert_eq!(r.qsubs.len(), 2);
+
+        c.process_error(NError::new(ERROR_CONNECTION_CLOSED), subs)
+            .await;
+        let sublist = c.sublist.read().unwrap();
+        assert_eq!(sublist.count(), 0);
+        assert_eq!(sublist.num_nodes(), 0);
+        assert!(sublist.subscriptions().is_empty());
+    }
+    #[tokio::test]
+    async fn test_info_and_empty_message() {
+        let srv = new_test_srv();
+        let (c, mut rx) = new_test_client(srv.clone(), 3).await;
+        c.send_info().await.unwrap();
+        assert_eq!(
+            received(&mut rx).as_slice(),
+            format!(
+                "INFO {{\"version\":\"{}\",\"client_id\":3,\"max_payload\":1048576}}\r\n",
+                env!("CARGO_PKG_VERSION")
+            )
+            .as_bytes()
+        );
+        //长度为0的消息也要推送给订阅者
+        let (receiver, mut receiver_rx) = new_test_channel();
+        let sub = Subscription::new("ping", None, "1", receiver.clone());
+        c.sublist.write().unwrap().insert(Arc::new(sub)).unwrap();
+        let pub_arg = PubArg {
+            subject: "ping".to_string(),
+            reply_to: None,
+            payload: Bytes::new(),
+        };
+        let mut cache = MatchCache::default();
+        let mut selector = QueueSelector::new(Default::default());
+        let mut pendings = PendingFrames::default();
+        c.process_pub(&pub_arg, None, &mut cache, &mut selector, &mut pendings)
+            .await
+            .unwrap();
+        pendings.flush();
+        assert_eq!(
+            received(&mut receiver_rx).as_slice(),
+            "MSG ping 1 0\r\n\r\n".as_bytes()
+        );
+        assert_eq!(receiver.pending_bytes(), "MSG ping 1 0\r\n\r\n".len());
+    }
+    #[tokio::test]
+    async fn test_unsub_and_ping() {
+        let srv = new_test_srv();
+        let (c, mut rx) = new_test_client(srv.clone(), 1).await;
+        let mut subs = HashMap::new();
+        let arg = SubArg {
+            subject: "foo".to_string(),
+            queue: None,
+            sid: "1".to_string(),
+        };
+        c.process_sub(&arg, &mut subs).await.unwrap();
+        let code = |r: crate::error::Result<()>| r.unwrap_err().err_code;
+        assert_eq!(
+            code(c.process_unsub("1", Some(10), &mut subs)),
+            ERROR_AUTO_UNSUBSCRIBE_NOT_SUPPORTED
+        );
+        c.process_unsub("1", None, &mut subs).unwrap();
+        assert!(subs.is_empty());
+        assert_eq!(c.sublist.read().unwrap().count(), 0);
+        assert_eq!(
+            code(c.process_unsub("1", None, &mut subs)),
+            ERROR_SUBSCRIBTION_NOT_FOUND
+        );
+        //同一个sid取消以后可以重新订阅
+        c.process_sub(&arg, &mut subs).await.unwrap();
+
+        let mut pendings = PendingFrames::default();
+        c.send_op(ServerOp::Pong, &mut pendings).await;
+        assert_eq!(pendings.len(), 1);
+        pendings.flush();
+        assert_eq!(received(&mut rx).as_slice(), "PONG\r\n".as_bytes());
+    }
+    //写任务要把排队的帧合并起来写完,收到结束标志以后关闭连接
+    #[tokio::test]
+    async fn test_writer_task() {
+        let (mut peer, conn) = new_test_conn().await;
+        let (_reader, writer) = tokio::io::split(conn);
+        let (sender, rx) = new_test_channel();
+        let mut expected = Vec::new();
+        for i in 0..200 {
+            let frame = format!("MSG foo 1 {}\r\n{}\r\n", i.to_string().len(), i);
+            expected.extend_from_slice(frame.as_bytes());
+            sender.add_pending(frame.len());
+            sender.send(Bytes::from(frame)).unwrap();
+        }
+        let big = Bytes::from(vec![b'x'; 256 * 1024]);
+        expected.extend_from_slice(&big);
+        sender.add_pending(big.len());
+        sender.send(big).unwrap();
+        sender.close();
+        assert!(sender.send(Bytes::from_static(b"PING\r\n")).is_err());
+        let (closed_tx, mut closed_rx) = oneshot::channel();
+        let task = tokio::spawn(writer_task(
+            sender.clone(),
+            rx,
+            writer,
+            Default::default(),
+            closed_tx,
+        ));
+        let mut buf = Vec::new();
+        peer.read_to_end(&mut buf).await.unwrap();
+        task.await.unwrap();
+        assert_eq!(buf, expected);
+        assert_eq!(sender.pending.load(AtomicOrdering::Relaxed), 0);
+        assert_eq!(sender.pending_bytes(), usize::max_value());
+        assert_eq!(
+            closed_rx.try_recv().unwrap().err_code,
+            ERROR_CONNECTION_CLOSED
+        );
+    }
+    //一直写不出去的时候,超过deadline就要放弃,并且告诉读任务原因
+    #[tokio::test]
+    async fn test_write_deadline() {
+        //peer从来不读
+        let (_peer, conn) = new_test_conn().await;
+        let (_reader, writer) = tokio::io::split(conn);
+        let (sender, rx) = new_test_channel();
+        let (closed_tx, closed_rx) = oneshot::channel();
+        let task = tokio::spawn(writer_task(
+            sender.clone(),
+            rx,
+            writer,
+            Arc::new(SharedLimits::new(Limits {
+                write_deadline: Duration::from_millis(100),
+                ..Default::default()
+            })),
+            closed_tx,
+        ));
+        //远远超过socket的发送和接收缓冲区
+        for _ in 0..64 {
+            let frame = Bytes::from(vec![b'x'; 1024 * 1024]);
+            sender.add_pending(frame.len());
+            sender.send(frame).unwrap();
+        }
+        let reason = closed_rx.await.unwrap();
+        assert_eq!(reason.err_code, ERROR_WRITE_DEADLINE_EXCEEDED);
+        task.await.unwrap();
+        assert!(sender.is_closed());
+        assert!(sender.send(Bytes::from_static(b"PING\r\n")).is_err());
+    }
+    //订阅以后再也不读的client,超时以后连接要断开,订阅也要清理掉
+    #[tokio::test]
+    async fn test_stalled_client() {
+        let srv = new_test_srv();
+        srv.lock().await.limits.store(Limits {
+            write_deadline: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let (mut peer, sender) = start_test_connection(&srv).await;
+        let sublist = srv.lock().await.sublist.clone();
+        peer.write_all(b"SUB foo 1\r\n").await.unwrap();
+        while sublist.read().unwrap().count() == 0 {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        let (publisher, _rx) = new_test_client(srv, 2).await;
+        let pub_arg = PubArg {
+            subject: "foo".to_string(),
+            reply_to: None,
+            payload: Bytes::from(vec![b'x'; 1024 * 1024]),
+        };
+        let mut pendings = PendingFrames::default();
+        let r = sublist.read().unwrap().match_subject("foo");
+        for _ in 0..64 {
+            publisher
+                .send_message(&r.psubs[0], &pub_arg, None, &mut pendings)
+                .await
+                .unwrap();
+            pendings.flush();
+        }
+        for _ in 0..100 {
+            if sublist.read().unwrap().count() == 0 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+        assert_eq!(sublist.read().unwrap().count(), 0);
+        assert!(sender.is_closed());
+    }
+    //写队列超过max_pending的订阅者直接断开,publisher不等它,同一个publisher的其他订阅者不受影响
+    #[tokio::test]
+    async fn test_slow_consumer() {
+        let srv = new_test_srv();
+        let (c, _rx) = new_test_client(srv, 1).await;
+        const MAX_PENDING: usize = 1024;
+        let (stalled, mut stalled_rx) = new_test_channel();
+        stalled
+            .max_pending
+            .store(MAX_PENDING, AtomicOrdering::Relaxed);
+        let (healthy, mut healthy_rx) = new_test_channel();
+        for (sid, sender) in [("1", &stalled), ("2", &healthy)].iter() {
+            let sub = Subscription::new("foo", None, sid, (*sender).clone());
+            c.sublist.write().unwrap().insert(Arc::new(sub)).unwrap();
+        }
+        let pub_arg = PubArg {
+            subject: "foo".to_string(),
+            reply_to: None,
+            payload: Bytes::from_static(b"hello"),
+        };
+        let mut cache = MatchCache::default();
+        let mut selector = QueueSelector::new(Default::default());
+        let mut pendings = PendingFrames::new(c.msg_sender.producer.clone());
+        let msg_len = "MSG foo 1 5\r\nhello\r\n".len();
+        let n = MAX_PENDING / msg_len * 2;
+        let mut expected = BytesMut::new();
+        let publish = async {
+            for _ in 0..n {
+                c.process_pub(&pub_arg, None, &mut cache, &mut selector, &mut pendings)
+                    .await
+                    .unwrap();
+                pendings.flush();
+                if !stalled.is_closed() && stalled.pending_bytes() >= MAX_PENDING / 2 {
+                    assert_eq!(pendings.take_congested().len(), 1);
+                }
+                assert!(pendings.take_congested().is_empty());
+                //正常的订阅者每次都读走,让出一次是为了不用完tokio每次poll的预算
+                encode_msg(&mut expected, "foo", "2", None, None, b"hello");
+                assert_eq!(received(&mut healthy_rx), expected.to_vec());
+                expected.clear();
+                let _ = tokio::task::yield_now().await;
+            }
+        };
+        timeout(Duration::from_secs(1), publish).await.unwrap();
+        assert!(!healthy.is_closed());
+        assert!(stalled.is_closed());
+        assert_eq!(
+            stalled_rx.abort.try_recv().unwrap().err_code,
+            ERROR_SLOW_CONSUMER
+        );
+        //超过max_pending以后的帧没有进队列,也没有记在publisher名下
+        let mut frames = 0;
+        stalled_rx.frames.close();
+        while let Some(frame) = stalled_rx.frames.recv().await {
+            frame.producer.release(frame.data.len());
+            frames += 1;
+        }
+        assert_eq!(frames, MAX_PENDING / msg_len);
+        assert_eq!(c.msg_sender.producer.pending(), 0);
+    }
+    //关闭的时候排了再多的帧也不会丢,最后是空的结束帧
+    #[tokio::test]
+    async fn test_close_keeps_queued() {
+        let (sender, mut rx) = new_test_channel();
+        for _ in 0..1000 {
+            sender.add_pending(5);
+            sender.send(Bytes::from_static(b"hello")).unwrap();
+        }
+        sender.close();
+        assert!(sender.is_closed());
+        assert!(sender.send(Bytes::from_static(b"hello")).is_err());
+        drop(sender);
+        let mut frames = Vec::new();
+        while let Some(frame) = rx.frames.recv().await {
+            frame.producer.release(frame.data.len());
+            frames.push(frame.data);
+        }
+        assert_eq!(frames.len(), 1001);
+        assert!(frames[..1000].iter().all(|f| f.as_ref() == b"hello"));
+        assert!(frames[1000].is_empty());
+        assert!(rx.abort.try_recv().is_err());
+    }
+    //主题带通配符的PUB回复-ERR,连接和它的订阅都还在
+    #[tokio::test]
+    async fn test_pub_invalid_subject() {
+        let srv = new_test_srv();
+        let (mut peer, sender) = start_test_connection(&srv).await;
+        let sublist = srv.lock().await.sublist.clone();
+        peer.write_all(b"SUB foo.bar 1\r\nPUB foo.* 1\r\nx\r\nHPUB foo.> 12 13\r\nNATS/1.0\r\n\r\nx\r\nPUB foo.bar 1\r\ny\r\nPING\r\n")
+            .await
+            .unwrap();
+        let expected = "-ERR 'invalid publish subject'\r\n-ERR 'invalid publish subject'\r\nMSG foo.bar 1 1\r\ny\r\nPONG\r\n";
+        let mut buf = Vec::new();
+        let mut tmp = [0u8; 1024];
+        while !String::from_utf8_lossy(&buf).ends_with("PONG\r\n") {
+            let n = timeout(Duration::from_secs(1), peer.read(&mut tmp))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(n > 0);
+            buf.extend_from_slice(&tmp[..n]);
+        }
+        assert!(String::from_utf8_lossy(&buf).ends_with(expected));
+        assert!(!sender.is_closed());
+        assert_eq!(sublist.read().unwrap().count(), 1);
+        assert_eq!(sender.stats.in_msgs.load(AtomicOrdering::Relaxed), 1);
+    }
+    #[test]
+    fn test_yield_budget() {
+        let mut limits = Limits::default();
+        let mut budget = YieldBudget::default();
+        for _ in 0..limits.yield_msgs - 1 {
+            assert!(!budget.consume(10, &limits));
+        }
+        assert!(budget.consume(10, &limits));
+        //大消息按字节数让出
+        assert!(!budget.consume(limits.yield_bytes - 1, &limits));
+        assert!(budget.consume(1, &limits));
+        assert_eq!(budget.msgs, 0);
+        //都是0的时候从不让出
+        limits.yield_msgs = 0;
+        limits.yield_bytes = 0;
+        for _ in 0..1000 {
+            assert!(!budget.consume(1024, &limits));
+        }
+    }
+    //大的payload所有订阅者共享同一块内存,每个订阅者只有自己的控制行
+    #[tokio::test]
+    async fn test_shared_payload() {
+        let srv = new_test_srv();
+        let (c, _rx) = new_test_client(srv.clone(), 1).await;
+        let mut receivers = Vec::new();
+        for i in 0..3 {
+            let (sender, rx) = new_test_channel();
+            let sid = i.to_string();
+            let sub = Subscription::new("foo", None, sid.as_str(), sender.clone());
+            c.sublist.write().unwrap().insert(Arc::new(sub)).unwrap();
+            receivers.push((sid, sender, rx));
+        }
+        let payload = Bytes::from(vec![b'x'; SHARED_PAYLOAD_MIN * 2]);
+        let pub_arg = PubArg {
+            subject: "foo".to_string(),
+            reply_to: Some("bar".to_string()),
+            payload: payload.clone(),
+        };
+        let mut cache = MatchCache::default();
+        let mut selector = QueueSelector::new(Default::default());
+        let mut pendings = PendingFrames::default();
+        for _ in 0..2 {
+            c.process_pub(&pub_arg, None, &mut cache, &mut selector, &mut pendings)
+                .await
+                .unwrap();
+        }
+        pendings.flush();
+        for (sid, sender, rx) in receivers.iter_mut() {
+            let mut expected = BytesMut::new();
+            for _ in 0..2 {
+                encode_msg(&mut expected, "foo", sid, Some("bar"), None, &payload);
+            }
+            assert_eq!(sender.pending_bytes(), expected.len());
+            let mut frames = Vec::new();
+            while let Some(Some(frame)) = rx.frames.recv().now_or_never() {
+                frames.push(frame.data);
+            }
+            //控制行,payload,\r\n和下一条的控制行,payload,\r\n
+            assert_eq!(frames.len(), 5);
+            assert_eq!(frames[1].as_ptr(), payload.as_ptr());
+            assert_eq!(frames[3].as_ptr(), payload.as_ptr());
+            assert_eq!(frames.concat(), expected.to_vec());
+        }
+    }
+    //带通配符的订阅收到的MSG是发布的主题,不管payload是不是共享的
+    #[tokio::test]
+    async fn test_msg_subject() {
+        let srv = new_test_srv();
+        let (c, _rx) = new_test_client(srv, 1).await;
+        let (sender, mut rx) = new_test_channel();
+        let sub = Subscription::new("foo.*", None, "1", sender);
+        c.sublist.write().unwrap().insert(Arc::new(sub)).unwrap();
+        let mut cache = MatchCache::default();
+        let mut selector = QueueSelector::new(Default::default());
+        let mut pendings = PendingFrames::default();
+        for size in [5, SHARED_PAYLOAD_MIN].iter() {
+            let pub_arg = PubArg {
+                subject: "foo.bar".to_string(),
+                reply_to: None,
+                payload: Bytes::from(vec![b'x'; *size]),
+            };
+            c.process_pub(&pub_arg, None, &mut cache, &mut selector, &mut pendings)
+                .await
+                .unwrap();
+            pendings.flush();
+            let mut expected = BytesMut::new();
+            encode_msg(&mut expected, "foo.bar", "1", None, None, &pub_arg.payload);
+            assert_eq!(received(&mut rx), expected.to_vec());
+        }
+    }
+    /*
+    100个订阅者,每条消息512K,比较共享payload和每个订阅者拷贝一份的开销,
+    两种做法都是完整的process_pub,flush,然后订阅者取走所有的帧,只有shared_payload_min不同
+    */
+    const FANOUT_SUBS: usize = 100;
+    const FANOUT_PAYLOAD: usize = 512 * 1024;
+    #[bench]
+    fn bench_fanout_shared_512k(b: &mut Bencher) {
+        bench_fanout(b, SHARED_PAYLOAD_MIN);
+    }
+    //原来的做法,每个订阅者都把payload拷贝到自己的缓冲区中
+    #[bench]
+    fn bench_fanout_copy_512k(b: &mut Bencher) {
+        bench_fanout(b, usize::max_value());
+    }
+    fn bench_fanout(b: &mut Bencher, shared_payload_min: usize) {
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let srv = new_test_srv();
+        let (mut c, _rx) = rt.block_on(new_test_client(srv, 1));
+        c.shared_payload_min = shared_payload_min;
+        let mut receivers = Vec::new();
+        for i in 0..FANOUT_SUBS {
+            let (sender, rx) = new_test_channel();
+            let sub = Subscription::new("foo", None, i.to_string().as_str(), sender);
+            c.sublist.write().unwrap().insert(Arc::new(sub)).unwrap();
+            receivers.push(rx);
+        }
+        let pub_arg = PubArg {
+            subject: "foo".to_string(),
+            reply_to: None,
+            payload: Bytes::from(vec![b'x'; FANOUT_PAYLOAD]),
+        };
+        let mut cache = MatchCache::default();
+        let mut selector = QueueSelector::new(Default::default());
+        let mut pendings = PendingFrames::default();
+        b.bytes = (FANOUT_SUBS * FANOUT_PAYLOAD) as u64;
+        b.iter(|| {
+            rt.block_on(async {
+                c.process_pub(&pub_arg, None, &mut cache, &mut selector, &mut pendings)
+                    .await
+                    .unwrap();
+                pendings.flush();
+            });
+            for rx in receivers.iter_mut() {
+                received(rx);
+            }
+        });
+    }
     #[bench]
     fn bench_gen_rng(b: &mut Bencher) {
         b.iter(|| {
@@ -0,0 +1,910 @@
+/**
+### 省内存的trie树
+TrieSubList每一层都是`HashMap<String, Box<TrieNode>>`,每个节点都要单独分配内存,
+token的String也是每个节点各存一份,订阅放在BTreeSet中,哪怕只有一个订阅也要分配一个BTree节点.
+物联网的场景下,每个设备都有自己的主题,一百万个订阅的时候内存就非常可观了.
+
+CompactSubList的做法:
+1. 所有节点都放在一个Vec中,用u32的下标互相引用,删除的节点放到free中重复使用
+2. token和queue的名字都放到TokenTable中,节点中只保存u32的id,相同的token只存一份
+3. 只有一个子节点的时候直接放在节点中,不多的时候用Vec线性查找,超过SMALL_CHILDREN_MAX才换成HashMap
+4. 只有一个订阅的时候也直接放在节点中,多了才单独分配SubSet
+
+这样一个节点只有40个字节,大部分节点都不需要额外分配内存.
+match结果的cache和TrieSubList共用SubResultCache.
+*/
+use crate::error::*;
+use crate::simple_sublist::*;
+use crate::sublist::{is_valid_literal_subject, is_valid_subject, split_subject, SubResultCache};
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+
+type NodeId = u32;
+type TokenId = u32;
+const ROOT: NodeId = 0;
+const NONE: NodeId = u32::max_value();
+//子节点超过这个数量就换成HashMap
+const SMALL_CHILDREN_MAX: usize = 8;
+
+/**
+token的驻留表,每个token只保存一份,并且记录引用次数,
+没有人使用的时候就删掉,id留给后面的token使用.
+*/
+#[derive(Debug, Default)]
+struct TokenTable {
+    ids: HashMap<Box<str>, TokenId>,
+    refs: Vec<u32>,
+    free: Vec<TokenId>,
+}
+impl TokenTable {
+    fn lookup(&self, token: &str) -> Option<TokenId> {
+        self.ids.get(token).cloned()
+    }
+    //返回token的id,同时引用次数加1
+    fn intern(&mut self, token: &str) -> TokenId {
+        if let Some(id) = self.lookup(token) {
+            self.refs[id as usize] += 1;
+            return id;
+        }
+        let id = if let Some(id) = self.free.pop() {
+            self.refs[id as usize] = 1;
+            id
+        } else {
+            self.refs.push(1);
+            (self.refs.len() - 1) as TokenId
+        };
+        self.ids.insert(token.into(), id);
+        id
+    }
+    //为了省内存,不保存id到token的映射,所以释放的时候要给出token本身
+    fn release(&mut self, token: &str) {
+        let id = self.ids[token];
+        let refs = &mut self.refs[id as usize];
+        *refs -= 1;
+        if *refs == 0 {
+            self.ids.remove(token);
+            self.free.push(id);
+        }
+    }
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.ids.len()
+    }
+    fn memory_usage(&self) -> usize {
+        //HashMap按照每一项的大小加上一个字节的控制位估算
+        self.ids.capacity() * (size_of::<(Box<str>, TokenId)>() + 1)
+            + self.ids.keys().map(|k| k.len()).sum::<usize>()
+            + (self.refs.capacity() + self.free.capacity()) * size_of::<u32>()
+    }
+}
+
+#[derive(Debug)]
+enum Children {
+    Empty,
+    One(TokenId, NodeId),
+    Small(Box<Vec<(TokenId, NodeId)>>),
+    Map(Box<HashMap<TokenId, NodeId>>),
+}
+impl Default for Children {
+    fn default() -> Self {
+        Children::Empty
+    }
+}
+impl Children {
+    fn get(&self, token: TokenId) -> Option<NodeId> {
+        match self {
+            Children::Empty => None,
+            Children::One(t, n) => {
+                if *t == token {
+                    Some(*n)
+                } else {
+                    None
+                }
+            }
+            Children::Small(v) => v.iter().find(|(t, _)| *t == token).map(|(_, n)| *n),
+            Children::Map(m) => m.get(&token).cloned(),
+        }
+    }
+    fn insert(&mut self, token: TokenId, node: NodeId) {
+        match self {
+            Children::Empty => *self = Children::One(token, node),
+            Children::One(t, n) => {
+                *self = Children::Small(Box::new(vec![(*t, *n), (token, node)]));
+            }
+            Children::Small(v) => {
+                if v.len() < SMALL_CHILDREN_MAX {
+                    v.push((token, node));
+                    return;
+                }
+                let mut m: HashMap<_, _> = v.drain(..).collect();
+                m.insert(token, node);
+                *self = Children::Map(Box::new(m));
+            }
+            Children::Map(m) => {
+                m.insert(token, node);
+            }
+        }
+    }
+    fn remove(&mut self, token: TokenId) {
+        match self {
+            Children::Empty => {}
+            Children::One(t, _) => {
+                if *t == token {
+                    *self = Children::Empty;
+                }
+            }
+            Children::Small(v) => {
+                if let Some(pos) = v.iter().position(|(t, _)| *t == token) {
+                    v.swap_remove(pos);
+                }
+                if v.len() == 1 {
+                    *self = Children::One(v[0].0, v[0].1);
+                }
+            }
+            Children::Map(m) => {
+                m.remove(&token);
+                //少到一半的时候再换回来,避免在边界上来回切换
+                if m.len() <= SMALL_CHILDREN_MAX / 2 {
+                    let v = m.drain().collect();
+                    *self = Children::Small(Box::new(v));
+                }
+            }
+        }
+    }
+    fn is_empty(&self) -> bool {
+        match self {
+            Children::Empty => true,
+            _ => false,
+        }
+    }
+    fn nodes<'a>(&'a self) -> Box<dyn Iterator<Item = NodeId> + 'a> {
+        match self {
+            Children::Empty => Box::new(std::iter::empty()),
+            Children::One(_, n) => Box::new(std::iter::once(*n)),
+            Children::Small(v) => Box::new(v.iter().map(|(_, n)| *n)),
+            Children::Map(m) => Box::new(m.values().cloned()),
+        }
+    }
+    fn memory_usage(&self) -> usize {
+        match self {
+            Children::Small(v) => size_of::<Vec<(TokenId, NodeId)>>() + v.capacity() * 8,
+            Children::Map(m) => size_of::<HashMap<TokenId, NodeId>>() + m.capacity() * (8 + 1),
+            _ => 0,
+        }
+    }
+}
+
+//一个节点上的多个订阅
+#[derive(Debug, Default)]
+struct SubSet {
+    subs: Vec<ArcSubscription>,
+    qsubs: Vec<(TokenId, Vec<ArcSubscription>)>,
+}
+impl SubSet {
+    fn insert(&mut self, sub: &ArcSubscription, tokens: &mut TokenTable) -> bool {
+        let subs = if let Some(ref q) = sub.queue {
+            let pos = match tokens.lookup(q) {
+                Some(t) => self.qsubs.iter().position(|(q, _)| *q == t),
+                None => None,
+            };
+            match pos {
+                Some(pos) => &mut self.qsubs[pos].1,
+                None => {
+                    self.qsubs.push((tokens.intern(q), Vec::new()));
+                    &mut self.qsubs.last_mut().unwrap().1
+                }
+            }
+        } else {
+            &mut self.subs
+        };
+        if subs.iter().any(|s| Arc::ptr_eq(s, sub)) {
+            return false;
+        }
+        subs.push(sub.clone());
+        true
+    }
+    fn remove(&mut self, sub: &ArcSubscription, tokens: &mut TokenTable) -> bool {
+        let subs = if let Some(ref q) = sub.queue {
+            let pos = match tokens.lookup(q) {
+                Some(t) => self.qsubs.iter().position(|(q, _)| *q == t),
+                None => None,
+            };
+            match pos {
+                Some(pos) => &mut self.qsubs[pos].1,
+                None => return false,
+            }
+        } else {
+            &mut self.subs
+        };
+        let pos = match subs.iter().position(|s| Arc::ptr_eq(s, sub)) {
+            Some(pos) => pos,
+            None => return false,
+        };
+        subs.swap_remove(pos);
+        //空的queue要去掉,同时释放queue的名字
+        if let Some(pos) = self.qsubs.iter().position(|(_, subs)| subs.is_empty()) {
+            self.qsubs.swap_remove(pos);
+            tokens.release(sub.queue.as_ref().unwrap());
+        }
+        true
+    }
+}
+#[derive(Debug)]
+enum NodeSubs {
+    Empty,
+    One(ArcSubscription),
+    Many(Box<SubSet>),
+}
+impl Default for NodeSubs {
+    fn default() -> Self {
+        NodeSubs::Empty
+    }
+}
+impl NodeSubs {
+    fn is_empty(&self) -> bool {
+        match self {
+            NodeSubs::Empty => true,
+            _ => false,
+        }
+    }
+    //返回false表示这个订阅已经存在了
+    fn insert(&mut self, sub: &ArcSubscription, tokens: &mut TokenTable) -> bool {
+        match self {
+            NodeSubs::Empty if sub.queue.is_none() => {
+                *self = NodeSubs::One(sub.clone());
+                return true;
+            }
+            NodeSubs::One(s) if Arc::ptr_eq(s, sub) => return false,
+            NodeSubs::Empty => *self = NodeSubs::Many(Box::new(SubSet::default())),
+            NodeSubs::One(s) => {
+                let set = SubSet {
+                    subs: vec![s.clone()],
+                    qsubs: Vec::new(),
+                };
+                *self = NodeSubs::Many(Box::new(set));
+            }
+            NodeSubs::Many(_) => {}
+        }
+        match self {
+            NodeSubs::Many(set) => set.insert(sub, tokens),
+            _ => unreachable!(),
+        }
+    }
+    fn remove(&mut self, sub: &ArcSubscription, tokens: &mut TokenTable) -> bool {
+        match self {
+            NodeSubs::Empty => false,
+            NodeSubs::One(s) => {
+                if !Arc::ptr_eq(s, sub) {
+                    return false;
+                }
+                *self = NodeSubs::Empty;
+                true
+            }
+            NodeSubs::Many(set) => {
+                if !set.remove(sub, tokens) {
+                    return false;
+                }
+                if set.qsubs.is_empty() && set.subs.len() <= 1 {
+                    *self = match set.subs.pop() {
+                        Some(s) => NodeSubs::One(s),
+                        None => NodeSubs::Empty,
+                    };
+                }
+                true
+            }
+        }
+    }
+    fn add_to_result(&self, r: &mut SubResult) {
+        match self {
+            NodeSubs::Empty => {}
+            NodeSubs::One(s) => r.psubs.push(s.clone()),
+            NodeSubs::Many(set) => {
+                r.psubs.extend(set.subs.iter().cloned());
+                for (_, subs) in set.qsubs.iter() {
+                    r.qsubs.push(subs.clone());
+                }
+            }
+        }
+    }
+    fn collect(&self, v: &mut Vec<ArcSubscription>) {
+        match self {
+            NodeSubs::Empty => {}
+            NodeSubs::One(s) => v.push(s.clone()),
+            NodeSubs::Many(set) => {
+                v.extend(set.subs.iter().cloned());
+                for (_, subs) in set.qsubs.iter() {
+                    v.extend(subs.iter().cloned());
+                }
+            }
+        }
+    }
+    fn memory_usage(&self) -> usize {
+        match self {
+            NodeSubs::Many(set) => {
+                size_of::<SubSet>()
+                    + set.subs.capacity() * size_of::<ArcSubscription>()
+                    + set.qsubs.capacity() * size_of::<(TokenId, Vec<ArcSubscription>)>()
+                    + set
+                        .qsubs
+                        .iter()
+                        .map(|(_, subs)| subs.capacity() * size_of::<ArcSubscription>())
+                        .sum::<usize>()
+            }
+            _ => 0,
+        }
+    }
+}
+
+/**
+和TrieNode不同,这里没有单独的Level,
+一个节点的子节点就是主题中下一个token对应的节点.
+*/
+#[derive(Debug)]
+struct CompactNode {
+    children: Children,
+    subs: NodeSubs,
+    pwc: NodeId, //*
+    fwc: NodeId, //>
+}
+impl Default for CompactNode {
+    fn default() -> Self {
+        Self {
+            children: Default::default(),
+            subs: Default::default(),
+            pwc: NONE,
+            fwc: NONE,
+        }
+    }
+}
+impl CompactNode {
+    fn is_empty(&self) -> bool {
+        self.subs.is_empty() && self.children.is_empty() && self.pwc == NONE && self.fwc == NONE
+    }
+}
+//从父节点到子节点的边,删除的时候用来回溯
+#[derive(Debug, Clone, Copy)]
+enum Edge {
+    Literal(TokenId),
+    Pwc,
+    Fwc,
+}
+
+#[derive(Debug)]
+pub struct CompactSubList {
+    cache: Mutex<SubResultCache>,
+    nodes: Vec<CompactNode>,
+    free: Vec<NodeId>,
+    tokens: TokenTable,
+    generation: u64,
+    count: usize,
+    counters: SubListCounters,
+}
+impl Default for CompactSubList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl CompactSubList {
+    pub fn new() -> Self {
+        Self {
+            cache: Default::default(),
+            nodes: vec![CompactNode::default()],
+            free: Vec::new(),
+            tokens: Default::default(),
+            generation: 0,
+            count: 0,
+            counters: Default::default(),
+        }
+    }
+    //估算树本身占用的内存,不包括订阅和cache
+    pub fn memory_usage(&self) -> usize {
+        let mut size = size_of::<Self>();
+        size += self.nodes.capacity() * size_of::<CompactNode>();
+        size += self.free.capacity() * size_of::<NodeId>();
+        for n in self.nodes.iter() {
+            size += n.children.memory_usage() + n.subs.memory_usage();
+        }
+        size + self.tokens.memory_usage()
+    }
+    fn alloc_node(&mut self) -> NodeId {
+        if let Some(id) = self.free.pop() {
+            return id;
+        }
+        self.nodes.push(CompactNode::default());
+        (self.nodes.len() - 1) as NodeId
+    }
+    fn free_node(&mut self, id: NodeId) {
+        self.nodes[id as usize] = CompactNode::default();
+        self.free.push(id);
+    }
+    fn node(&self, id: NodeId) -> &CompactNode {
+        &self.nodes[id as usize]
+    }
+    fn child(&self, id: NodeId, edge: Edge) -> NodeId {
+        let n = self.node(id);
+        match edge {
+            Edge::Pwc => n.pwc,
+            Edge::Fwc => n.fwc,
+            Edge::Literal(t) => n.children.get(t).unwrap_or(NONE),
+        }
+    }
+    //只查找,不修改引用计数
+    fn lookup_edge(&self, token: &str) -> Option<Edge> {
+        match token {
+            "*" => Some(Edge::Pwc),
+            ">" => Some(Edge::Fwc),
+            _ => self.tokens.lookup(token).map(Edge::Literal),
+        }
+    }
+    fn match_node(&self, id: NodeId, tokens: &[Option<TokenId>], r: &mut SubResult) {
+        let n = self.node(id);
+        let rest = &tokens[1..];
+        //match >
+        if n.fwc != NONE {
+            self.node(n.fwc).subs.add_to_result(r);
+        }
+        //match *
+        if n.pwc != NONE {
+            self.match_next(n.pwc, rest, r);
+        }
+        //match exactly
+        if let Some(c) = tokens[0].and_then(|t| n.children.get(t)) {
+            self.match_next(c, rest, r);
+        }
+    }
+    fn match_next(&self, id: NodeId, rest: &[Option<TokenId>], r: &mut SubResult) {
+        if rest.is_empty() {
+            self.node(id).subs.add_to_result(r);
+        } else {
+            self.match_node(id, rest, r);
+        }
+    }
+    fn child_nodes<'a>(&'a self, id: NodeId) -> impl Iterator<Item = NodeId> + 'a {
+        let n = self.node(id);
+        n.children
+            .nodes()
+            .chain(Some(n.pwc))
+            .chain(Some(n.fwc))
+            .filter(|c| *c != NONE)
+    }
+    //id下面最深有多少层
+    fn depth(&self, id: NodeId) -> usize {
+        self.child_nodes(id)
+            .map(|c| 1 + self.depth(c))
+            .max()
+            .unwrap_or(0)
+    }
+    //id以及id下面所有的订阅
+    fn collect_node(&self, id: NodeId, v: &mut Vec<ArcSubscription>) {
+        self.node(id).subs.collect(v);
+        for c in self.child_nodes(id) {
+            self.collect_node(c, v);
+        }
+    }
+    /*
+    规则和TrieSubList::reverse_match_level一样,
+    pattern中的*可以匹配普通节点和*节点,>可以匹配剩下的所有节点
+    */
+    fn reverse_match_node(&self, id: NodeId, tokens: &[&str], v: &mut Vec<ArcSubscription>) {
+        let n = self.node(id);
+        if tokens.is_empty() {
+            n.subs.collect(v);
+            return;
+        }
+        let rest = &tokens[1..];
+        match tokens[0] {
+            ">" => {
+                for c in self.child_nodes(id) {
+                    self.collect_node(c, v);
+                }
+            }
+            "*" => {
+                for c in n.children.nodes().chain(Some(n.pwc)).filter(|c| *c != NONE) {
+                    self.reverse_match_node(c, rest, v);
+                }
+            }
+            token => {
+                if let Some(c) = self.tokens.lookup(token).and_then(|t| n.children.get(t)) {
+                    self.reverse_match_node(c, rest, v);
+                }
+            }
+        }
+    }
+}
+impl SubListTrait for CompactSubList {
+    fn insert(&mut self, sub: ArcSubscription) -> Result<()> {
+        if !is_valid_subject(sub.subject.as_str()) {
+            return Err(NError::new(ERROR_INVALID_SUBJECT));
+        }
+        let mut id = ROOT;
+        for token in split_subject(sub.subject.as_str()) {
+            let edge = self.lookup_edge(token);
+            let c = edge.map(|e| self.child(id, e)).unwrap_or(NONE);
+            if c != NONE {
+                id = c;
+                continue;
+            }
+            let c = self.alloc_node();
+            match token {
+                "*" => self.nodes[id as usize].pwc = c,
+                ">" => self.nodes[id as usize].fwc = c,
+                _ => {
+                    let t = self.tokens.intern(token);
+                    self.nodes[id as usize].children.insert(t, c);
+                }
+            }
+            id = c;
+        }
+        if self.nodes[id as usize].subs.insert(&sub, &mut self.tokens) {
+            self.count += 1;
+        }
+        self.cache.get_mut().unwrap().insert(sub);
+        self.generation += 1;
+        self.counters.record_insert();
+        Ok(())
+    }
+    fn remove(&mut self, sub: ArcSubscription) -> Result<()> {
+        if !is_valid_subject(sub.subject.as_str()) {
+            return Err(NError::new(ERROR_INVALID_SUBJECT));
+        }
+        //记录下走过的路径,删除以后把空的节点一路回收
+        let mut path = Vec::new();
+        let mut id = ROOT;
+        for token in split_subject(sub.subject.as_str()) {
+            let edge = match self.lookup_edge(token) {
+                Some(edge) => edge,
+                None => return Err(NError::new(ERROR_SUBSCRIBTION_NOT_FOUND)),
+            };
+            let c = self.child(id, edge);
+            if c == NONE {
+                return Err(NError::new(ERROR_SUBSCRIBTION_NOT_FOUND));
+            }
+            path.push((id, edge, token));
+            id = c;
+        }
+        if !self.nodes[id as usize].subs.remove(&sub, &mut self.tokens) {
+            return Err(NError::new(ERROR_SUBSCRIBTION_NOT_FOUND));
+        }
+        while let Some((parent, edge, token)) = path.pop() {
+            if !self.node(id).is_empty() {
+                break;
+            }
+            let p = &mut self.nodes[parent as usize];
+            match edge {
+                Edge::Pwc => p.pwc = NONE,
+                Edge::Fwc => p.fwc = NONE,
+                Edge::Literal(t) => {
+                    p.children.remove(t);
+                    self.tokens.release(token);
+                }
+            }
+            self.free_node(id);
+            id = parent;
+        }
+        self.cache.get_mut().unwrap().remove(&sub);
+        self.generation += 1;
+        self.count -= 1;
+        self.counters.record_remove();
+        Ok(())
+    }
+    fn match_subject(&self, subject: &str) -> ArcSubResult {
+        //别人正在用cache的话就不等了,直接查树
+        if let Ok(mut cache) = self.cache.try_lock() {
+            if let Some(r) = cache.get(subject) {
+                self.counters.record_match(&r, true);
+                return r;
+            }
+        }
+        //带通配符的主题不会匹配任何订阅,也不缓存
+        if !is_valid_literal_subject(subject) {
+            return Arc::new(SubResult::new());
+        }
+        //树中没有的token只能被*和>匹配
+        let tokens: Vec<_> = split_subject(subject)
+            .map(|t| self.tokens.lookup(t))
+            .collect();
+        let mut r = SubResult::new();
+        self.match_node(ROOT, tokens.as_slice(), &mut r);
+        self.counters.record_match(&r, false);
+        let r = Arc::new(r);
+        if let Ok(mut cache) = self.cache.try_lock() {
+            cache.insert_result(subject, r.clone());
+        }
+        r
+    }
+    fn generation(&self) -> u64 {
+        self.generation
+    }
+    fn count(&self) -> usize {
+        self.count
+    }
+    fn num_levels(&self) -> usize {
+        self.depth(ROOT)
+    }
+    fn num_nodes(&self) -> usize {
+        //不包括根节点
+        self.nodes.len() - self.free.len() - 1
+    }
+    fn subscriptions(&self) -> Vec<ArcSubscription> {
+        let mut v = Vec::with_capacity(self.count);
+        self.collect_node(ROOT, &mut v);
+        v
+    }
+    fn reverse_match(&self, pattern: &str) -> Vec<ArcSubscription> {
+        let mut v = Vec::new();
+        if !is_valid_subject(pattern) {
+            return v;
+        }
+        let tokens: Vec<_> = split_subject(pattern).collect();
+        self.reverse_match_node(ROOT, tokens.as_slice(), &mut v);
+        v
+    }
+    fn stats(&self) -> SubListStats {
+        let num_cache = self.cache.lock().map(|c| c.len()).unwrap_or(0);
+        self.counters.stats(self.count, num_cache)
+    }
+    fn set_cache_size(&mut self, cache_size: usize) {
+        self.cache.lock().unwrap().resize(cache_size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::new_test_tcp_writer;
+    use crate::simple_sublist::tests::{random_subject, sorted_result};
+    use crate::sublist::TrieSubList;
+    use rand::{Rng, SeedableRng};
+
+    fn new_sub(subject: &str, queue: Option<&str>) -> ArcSubscription {
+        Arc::new(Subscription::new(subject, queue, "1", new_test_tcp_writer()))
+    }
+    #[test]
+    fn test_match() {
+        let mut s = CompactSubList::new();
+        let lsub = new_sub("a.b.c", None);
+        let psub = new_sub("a.*.c", None);
+        let fsub = new_sub("a.>", None);
+        let qsub = new_sub("a.b.c", Some("q"));
+        for sub in [&lsub, &psub, &fsub, &qsub].iter() {
+            s.insert((*sub).clone()).unwrap();
+        }
+        assert!(s.insert(new_sub("a.>.c", None)).is_err());
+        assert_eq!(s.count(), 4);
+        let r = s.match_subject("a.b.c");
+        assert_eq!(r.psubs.len(), 3);
+        assert_eq!(r.qsubs.len(), 1);
+        assert!(Arc::ptr_eq(&r.qsubs[0][0], &qsub));
+        let r = s.match_subject("a.x.c");
+        assert_eq!(r.psubs.len(), 2);
+        let r = s.match_subject("a");
+        assert!(r.psubs.is_empty());
+        for subject in ["a.*.c", "a.>", "a..c", ""].iter() {
+            let r = s.match_subject(subject);
+            assert!(r.psubs.is_empty() && r.qsubs.is_empty());
+        }
+        assert_eq!(s.num_levels(), 3);
+        assert_eq!(s.num_nodes(), 6);
+        assert_eq!(s.reverse_match("a.*.c").len(), 3);
+        assert_eq!(s.subscriptions().len(), 4);
+    }
+    //两种实现对带通配符或者不合法的主题都返回空的结果,也不缓存
+    fn check_match_invalid<T: SubListTrait + Default>() {
+        let mut s = T::default();
+        s.insert(new_sub("a.>", None)).unwrap();
+        s.insert(new_sub(">", None)).unwrap();
+        for subject in ["a.*", "a.>", ">", "a..b", ".a", ""].iter() {
+            let r = s.match_subject(subject);
+            assert!(r.psubs.is_empty() && r.qsubs.is_empty(), "{}", subject);
+        }
+        assert_eq!(s.stats().num_cache, 0);
+        assert_eq!(s.match_subject("a.b").psubs.len(), 2);
+    }
+    #[test]
+    fn test_match_invalid_subject() {
+        check_match_invalid::<TrieSubList>();
+        check_match_invalid::<CompactSubList>();
+    }
+    #[test]
+    fn test_remove_cleanup() {
+        let mut s = CompactSubList::new();
+        let subs: Vec<_> = (0..20)
+            .map(|i| new_sub(format!("foo.{}.bar", i).as_str(), Some("q")))
+            .collect();
+        for sub in subs.iter() {
+            s.insert(sub.clone()).unwrap();
+        }
+        //foo, 20个数字, 20个bar
+        assert_eq!(s.num_nodes(), 41);
+        assert_eq!(s.tokens.len(), 23);
+        for sub in subs.iter() {
+            s.remove(sub.clone()).unwrap();
+            assert!(s.remove(sub.clone()).is_err());
+        }
+        assert_eq!(s.count(), 0);
+        assert_eq!(s.num_nodes(), 0);
+        assert_eq!(s.num_levels(), 0);
+        assert_eq!(s.tokens.len(), 0);
+        assert!(s.node(ROOT).is_empty());
+        //节点和token的id都会重复使用
+        let nodes = s.nodes.len();
+        for sub in subs.iter() {
+            s.insert(sub.clone()).unwrap();
+        }
+        assert_eq!(s.nodes.len(), nodes);
+        assert_eq!(s.tokens.refs.len(), 23);
+    }
+    #[test]
+    fn test_children_grow_and_shrink() {
+        let mut c = Children::default();
+        for i in 0..20 {
+            c.insert(i, i + 100);
+        }
+        assert!(match c {
+            Children::Map(_) => true,
+            _ => false,
+        });
+        for i in 0..20 {
+            assert_eq!(c.get(i), Some(i + 100));
+        }
+        for i in 0..17 {
+            c.remove(i);
+        }
+        assert!(match c {
+            Children::Small(_) => true,
+            _ => false,
+        });
+        assert_eq!(c.get(17), Some(117));
+        assert_eq!(c.get(3), None);
+        assert_eq!(c.nodes().count(), 3);
+        c.remove(17);
+        c.remove(18);
+        assert!(match c {
+            Children::One(19, 119) => true,
+            _ => false,
+        });
+        c.remove(19);
+        assert!(c.is_empty());
+    }
+    //和TrieSubList做同样的操作,结果必须完全一致
+    #[test]
+    fn test_differential_with_trie() {
+        for seed in 0..20 {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            let mut compact = CompactSubList::new();
+            let mut trie = TrieSubList::new();
+            let mut subs: Vec<ArcSubscription> = Vec::new();
+            for _ in 0..500 {
+                match rng.gen_range(0, 4) {
+                    0 => {
+                        let subject = random_subject(&mut rng, true);
+                        let queue = [None, Some("q1"), Some("q2")][rng.gen_range(0, 3)];
+                        let sub = new_sub(subject.as_str(), queue);
+                        assert!(compact.insert(sub.clone()).is_ok());
+                        assert!(trie.insert(sub.clone()).is_ok());
+                        subs.push(sub);
+                    }
+                    1 if !subs.is_empty() => {
+                        let sub = subs.swap_remove(rng.gen_range(0, subs.len()));
+                        assert!(compact.remove(sub.clone()).is_ok());
+                        assert!(trie.remove(sub.clone()).is_ok());
+                        assert!(compact.remove(sub).is_err());
+                    }
+                    2 => {
+                        let pattern = random_subject(&mut rng, true);
+                        let addrs = |v: Vec<ArcSubscription>| {
+                            let mut r = SubResult::new();
+                            r.psubs = v;
+                            sorted_result(&r)
+                        };
+                        assert_eq!(
+                            addrs(compact.reverse_match(pattern.as_str())),
+                            addrs(trie.reverse_match(pattern.as_str())),
+                            "seed={} pattern={}",
+                            seed,
+                            pattern
+                        );
+                    }
+                    _ => {
+                        let subject = random_subject(&mut rng, false);
+                        let r1 = compact.match_subject(subject.as_str());
+                        let r2 = trie.match_subject(subject.as_str());
+                        assert_eq!(
+                            sorted_result(&r1),
+                            sorted_result(&r2),
+                            "seed={} subject={}",
+                            seed,
+                            subject
+                        );
+                    }
+                }
+                assert_eq!(compact.count(), trie.count());
+                assert_eq!(compact.num_nodes(), trie.num_nodes());
+                assert_eq!(compact.num_levels(), trie.num_levels());
+            }
+        }
+    }
+}
+
+/**
+比较CompactSubList和TrieSubList的内存占用.
+测试的时候用test_allocator::allocated统计当前线程分配的内存,
+订阅本身在插入之前就创建好了,所以统计的只是树的开销.
+一百万个订阅的测试每次都跑,debug下一共要分配六百多M,单独看输出的数字:
+cargo test --release memory_1m -- --nocapture
+*/
+#[cfg(test)]
+mod benchmark {
+    use super::*;
+    use crate::client::new_test_tcp_writer;
+    use crate::sublist::TrieSubList;
+    use crate::test_allocator::allocated;
+
+    //模拟物联网的场景,每个设备订阅自己的主题,再加上少量的通配符和queue订阅
+    fn create_iot_subs(n: usize) -> Vec<ArcSubscription> {
+        let writer = new_test_tcp_writer();
+        let mut subs = Vec::with_capacity(n);
+        for i in 0..n {
+            let subject = format!("iot.region{}.device{}.cmd", i % 16, i);
+            let queue = if i % 100 == 0 { Some("workers") } else { None };
+            subs.push(Arc::new(Subscription::new(
+                subject.as_str(),
+                queue,
+                "1",
+                writer.clone(),
+            )));
+        }
+        for i in 0..16 {
+            let subject = format!("iot.region{}.*.cmd", i);
+            subs.push(Arc::new(Subscription::new(
+                subject.as_str(),
+                None,
+                "1",
+                writer.clone(),
+            )));
+        }
+        subs
+    }
+    //返回建好的sublist占用的内存
+    fn measure<T: SubListTrait + Default>(subs: &[ArcSubscription]) -> (usize, T) {
+        let before = allocated();
+        let mut s = T::default();
+        for sub in subs {
+            s.insert(sub.clone()).unwrap();
+        }
+        ((allocated() - before) as usize, s)
+    }
+    /**
+    只比较sublist本身占用的内存,订阅是事先建好的,两种sublist共用.
+    订阅中subject,sid,queue的String每个订阅各存一份,这部分不在比较范围之内,单独打印出来.
+    */
+    fn compare_memory(n: usize) -> (usize, usize) {
+        let before = allocated();
+        let subs = create_iot_subs(n);
+        let subs_size = (allocated() - before) as usize;
+        let (trie_size, trie) = measure::<TrieSubList>(subs.as_slice());
+        drop(trie);
+        let (compact_size, compact) = measure::<CompactSubList>(subs.as_slice());
+        println!(
+            "subscriptions={} subs={}KB trie={}KB compact={}KB estimated={}KB",
+            subs.len(),
+            subs_size / 1024,
+            trie_size / 1024,
+            compact_size / 1024,
+            compact.memory_usage() / 1024
+        );
+        (trie_size, compact_size)
+    }
+    #[test]
+    fn test_memory_usage() {
+        let (trie_size, compact_size) = compare_memory(10_000);
+        assert!(compact_size * 2 < trie_size);
+    }
+    //readme中的数字就是这个测试打出来的,trie大约494M,compact大约165M,不到35%
+    #[test]
+    fn test_memory_usage_1m() {
+        let (trie_size, compact_size) = compare_memory(1_000_000);
+        assert!(compact_size * 100 < trie_size * 35);
+    }
+}
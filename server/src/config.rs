@@ -0,0 +1,308 @@
+/**
+### 配置文件
+json格式,所有的字段都可以省略,省略的用默认值,比如:
+```text
+{"port":4222,"log_level":"debug","log_file":"/var/log/nats.log","max_payload":2097152}
+```
+收到SIGHUP,管理接口的RELOAD命令或者调用ServerHandle::reload的时候,新的配置和正在运行的配置比较,
+监听地址,端口,管理接口地址,sublist类型这些必须重启才能生效的修改直接拒绝,
+日志级别,各种限制,cache大小则在运行中生效,已有的连接不会断开,queue的负载均衡策略只对新的连接生效.
+*/
+use crate::limits::Limits;
+use crate::queue_strategy::QueueStrategyConfig;
+use crate::server::SubListType;
+use crate::sublist::SL_CACHE_MAX;
+use log::LevelFilter;
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::Debug;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub addr: String,
+    pub port: u16,
+    //本地管理接口的地址,比如127.0.0.1:8222,没有的话不开启,见admin
+    pub admin_addr: Option<String>,
+    pub sublist: SubListType,
+    //sublist最多缓存多少个主题的匹配结果
+    pub sublist_cache_size: usize,
+    //off,error,warn,info,debug,trace
+    pub log_level: String,
+    //打印所有收到的控制行和发出的MSG控制行,日志级别也会变成trace
+    pub trace: bool,
+    //没有的话输出到stderr
+    pub log_file: Option<String>,
+    //日志文件超过这个大小以后轮转,0表示不轮转
+    pub log_size_limit: u64,
+    //轮转的时候最多保留几个旧的日志文件
+    pub log_max_files: usize,
+    //lame duck模式下在这段时间内逐步断开所有的连接
+    #[serde(rename = "lame_duck_duration_ms", with = "duration_ms")]
+    pub lame_duck_duration: Duration,
+    pub queue_strategies: QueueStrategyConfig,
+    #[serde(flatten)]
+    pub limits: Limits,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            addr: "0.0.0.0".to_string(),
+            port: 4222,
+            admin_addr: None,
+            sublist: SubListType::default(),
+            sublist_cache_size: SL_CACHE_MAX,
+            log_level: "info".to_string(),
+            trace: false,
+            log_file: None,
+            log_size_limit: 0,
+            log_max_files: 5,
+            lame_duck_duration: Duration::from_secs(30),
+            queue_strategies: QueueStrategyConfig::default(),
+            limits: Limits::default(),
+        }
+    }
+}
+impl Config {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let data = std::fs::read_to_string(path)?;
+        let config: Config = serde_json::from_str(&data)?;
+        config.validate()?;
+        Ok(config)
+    }
+    pub fn level_filter(&self) -> Result<LevelFilter, Box<dyn Error>> {
+        self.log_level
+            .parse()
+            .map_err(|_| format!("unknown log level {}", self.log_level).into())
+    }
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        self.level_filter()?;
+        if self.sublist_cache_size == 0 {
+            return Err("sublist_cache_size must be greater than 0".into());
+        }
+        Ok(())
+    }
+    /**
+    和正在运行的配置比较,返回所有的修改,形如`max_payload: 1048576 -> 2097152`.
+    有任何需要重启才能生效的修改都返回错误,这时候其他的修改也不会生效.
+    */
+    pub fn diff(&self, new: &Config) -> Result<Vec<String>, Box<dyn Error>> {
+        new.validate()?;
+        if self.addr != new.addr || self.port != new.port {
+            return Err(format!(
+                "listen address {}:{} -> {}:{} requires restart",
+                self.addr, self.port, new.addr, new.port
+            )
+            .into());
+        }
+        if self.admin_addr != new.admin_addr {
+            return Err(format!(
+                "admin address {:?} -> {:?} requires restart",
+                self.admin_addr, new.admin_addr
+            )
+            .into());
+        }
+        if self.sublist != new.sublist {
+            return Err(format!(
+                "sublist {:?} -> {:?} requires restart",
+                self.sublist, new.sublist
+            )
+            .into());
+        }
+        let mut changes = Vec::new();
+        let (old_limits, new_limits) = (&self.limits, &new.limits);
+        changed(&mut changes, "log_level", &self.log_level, &new.log_level);
+        changed(&mut changes, "trace", &self.trace, &new.trace);
+        changed(&mut changes, "log_file", &self.log_file, &new.log_file);
+        changed(
+            &mut changes,
+            "log_size_limit",
+            &self.log_size_limit,
+            &new.log_size_limit,
+        );
+        changed(
+            &mut changes,
+            "log_max_files",
+            &self.log_max_files,
+            &new.log_max_files,
+        );
+        changed(
+            &mut changes,
+            "sublist_cache_size",
+            &self.sublist_cache_size,
+            &new.sublist_cache_size,
+        );
+        changed(
+            &mut changes,
+            "lame_duck_duration",
+            &self.lame_duck_duration,
+            &new.lame_duck_duration,
+        );
+        changed(
+            &mut changes,
+            "queue_strategies",
+            &self.queue_strategies,
+            &new.queue_strategies,
+        );
+        changed(
+            &mut changes,
+            "max_subject_len",
+            &old_limits.max_subject_len,
+            &new_limits.max_subject_len,
+        );
+        changed(
+            &mut changes,
+            "max_subject_tokens",
+            &old_limits.max_subject_tokens,
+            &new_limits.max_subject_tokens,
+        );
+        changed(
+            &mut changes,
+            "max_subs_per_conn",
+            &old_limits.max_subs_per_conn,
+            &new_limits.max_subs_per_conn,
+        );
+        changed(
+            &mut changes,
+            "max_total_subs",
+            &old_limits.max_total_subs,
+            &new_limits.max_total_subs,
+        );
+        changed(
+            &mut changes,
+            "max_payload",
+            &old_limits.max_payload,
+            &new_limits.max_payload,
+        );
+        changed(
+            &mut changes,
+            "write_deadline",
+            &old_limits.write_deadline,
+            &new_limits.write_deadline,
+        );
+        changed(
+            &mut changes,
+            "rate_limit",
+            &old_limits.rate_limit,
+            &new_limits.rate_limit,
+        );
+        changed(
+            &mut changes,
+            "user_rate_limits",
+            &old_limits.user_rate_limits,
+            &new_limits.user_rate_limits,
+        );
+        changed(
+            &mut changes,
+            "max_pending_bytes",
+            &old_limits.max_pending_bytes,
+            &new_limits.max_pending_bytes,
+        );
+        changed(
+            &mut changes,
+            "max_pending_per_conn",
+            &old_limits.max_pending_per_conn,
+            &new_limits.max_pending_per_conn,
+        );
+        changed(
+            &mut changes,
+            "yield_msgs",
+            &old_limits.yield_msgs,
+            &new_limits.yield_msgs,
+        );
+        changed(
+            &mut changes,
+            "yield_bytes",
+            &old_limits.yield_bytes,
+            &new_limits.yield_bytes,
+        );
+        Ok(changes)
+    }
+}
+fn changed<T: Debug + PartialEq>(changes: &mut Vec<String>, name: &str, old: &T, new: &T) {
+    if old != new {
+        changes.push(format!("{}: {:?} -> {:?}", name, old, new));
+    }
+}
+//配置文件中的时间都以毫秒为单位
+pub(crate) mod duration_ms {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+    pub fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(d.as_millis() as u64)
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        u64::deserialize(d).map(Duration::from_millis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue_strategy::QueueStrategy;
+    #[test]
+    fn test_parse() {
+        let config: Config = serde_json::from_str(
+            r#"{"port":4333,"sublist":"compact","log_level":"debug","max_payload":2048,"write_deadline_ms":500,
+            "yield_msgs":0,"yield_bytes":0,"queue_strategies":{"default":"round_robin","queues":{"jobs":"sticky"}}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.port, 4333);
+        assert_eq!(config.sublist, SubListType::Compact);
+        assert_eq!(config.level_filter().unwrap(), LevelFilter::Debug);
+        assert_eq!(config.limits.max_payload, 2048);
+        assert_eq!(config.limits.write_deadline, Duration::from_millis(500));
+        //limits中的字段直接写在最外层
+        assert_eq!(
+            (config.limits.yield_msgs, config.limits.yield_bytes),
+            (0, 0)
+        );
+        assert_eq!(config.queue_strategies.default, QueueStrategy::RoundRobin);
+        assert_eq!(
+            config.queue_strategies.strategy("jobs"),
+            QueueStrategy::Sticky
+        );
+        //没有指定的用默认值
+        assert_eq!(config.addr, "0.0.0.0");
+        assert_eq!(config.sublist_cache_size, SL_CACHE_MAX);
+        assert_eq!(
+            config.limits.max_subject_len,
+            Limits::default().max_subject_len
+        );
+        let s = serde_json::to_string(&config).unwrap();
+        assert_eq!(serde_json::from_str::<Config>(&s).unwrap(), config);
+    }
+    #[test]
+    fn test_diff() {
+        let old = Config::default();
+        assert!(old.diff(&old.clone()).unwrap().is_empty());
+        let mut new = old.clone();
+        new.log_level = "debug".to_string();
+        new.trace = true;
+        new.limits.max_payload = 2048;
+        assert_eq!(
+            old.diff(&new).unwrap(),
+            vec![
+                "log_level: \"info\" -> \"debug\"".to_string(),
+                "trace: false -> true".to_string(),
+                "max_payload: 1048576 -> 2048".to_string(),
+            ]
+        );
+        //需要重启的修改
+        let mut new = old.clone();
+        new.port = 4333;
+        let e = old.diff(&new).unwrap_err().to_string();
+        assert!(e.contains("requires restart"), "{}", e);
+        let mut new = old.clone();
+        new.sublist = SubListType::Compact;
+        assert!(old.diff(&new).is_err());
+        let mut new = old.clone();
+        new.admin_addr = Some("127.0.0.1:8222".to_string());
+        assert!(old.diff(&new).is_err());
+        //不合法的配置
+        let mut new = old.clone();
+        new.log_level = "verbose".to_string();
+        assert!(old.diff(&new).is_err());
+    }
+}
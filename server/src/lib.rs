@@ -0,0 +1,51 @@
+/*!
+nats server,既可以作为单独的程序运行,也可以嵌入到其他程序中,
+比如集成测试中同一个进程可以启动多个server:
+```no_run
+# async fn run() -> Result<(), Box<dyn std::error::Error>> {
+let handle = nats_server::ServerBuilder::new()
+    .addr("127.0.0.1")
+    .port(0)
+    .start()
+    .await?;
+println!("listen on {}", handle.local_addr());
+handle.shutdown().await;
+# Ok(())
+# }
+```
+*/
+#![feature(test)]
+#![feature(hash_raw_entry)]
+
+//测试的时候统计内存分配,见compact_sublist的benchmark
+#[cfg(test)]
+#[global_allocator]
+static GLOBAL: test_allocator::CountingAllocator = test_allocator::CountingAllocator;
+
+mod admin;
+mod client;
+mod compact_sublist;
+mod config;
+mod error;
+mod limits;
+mod logger;
+mod memory_budget;
+mod queue_strategy;
+mod rate_limit;
+mod server;
+mod simple_sublist;
+mod sublist;
+#[cfg(test)]
+mod test_allocator;
+
+pub use crate::admin::{AdminCommand, AdminRequest};
+pub use crate::compact_sublist::CompactSubList;
+pub use crate::config::Config;
+pub use crate::limits::Limits;
+pub use crate::logger::Logger;
+pub use crate::memory_budget::MemoryUsage;
+pub use crate::queue_strategy::{QueueStrategy, QueueStrategyConfig};
+pub use crate::rate_limit::RateLimit;
+pub use crate::server::{ConnInfo, Server, ServerBuilder, ServerHandle, SubListType};
+pub use crate::simple_sublist::SubListTrait;
+pub use crate::sublist::TrieSubList;
@@ -0,0 +1,82 @@
+/**
+### 订阅和消息相关的限制
+防止某个client用特别长的主题或者特别多的订阅把服务器拖垮.
+超过限制的SUB会收到`-ERR`,但是连接不会断开,之前的订阅也都还有效.
+max_payload会通过INFO告诉client,超过的PUB会导致连接断开.
+写socket超过write_deadline还没有任何进展,说明对方已经不读了,连接也会被断开.
+发布超过rate_limit的连接会暂停读,见rate_limit.
+所有连接的写队列加起来超过max_pending_bytes,发布最多的连接会暂停读,见memory_budget.
+一个连接的写队列超过max_pending_per_conn的一半,给它推消息的连接也会暂停读,
+还是超过了max_pending_per_conn的话,这个连接作为慢消费者断开.
+这些限制都可以通过重新加载配置在运行中修改,见SharedLimits.
+*/
+use crate::rate_limit::RateLimit;
+use protocol::DEFAULT_MAX_PAYLOAD;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    //主题的最大字节数
+    pub max_subject_len: usize,
+    //主题最多有几段,比如a.b.c是3段
+    pub max_subject_tokens: usize,
+    //每个连接最多有多少个订阅
+    pub max_subs_per_conn: usize,
+    //整个服务器最多有多少个订阅
+    pub max_total_subs: usize,
+    //消息体的最大字节数
+    pub max_payload: usize,
+    //一次写socket最多等多久
+    #[serde(rename = "write_deadline_ms", with = "crate::config::duration_ms")]
+    pub write_deadline: Duration,
+    //每个连接发布的速率限制
+    pub rate_limit: RateLimit,
+    //按照CONNECT中的user限制,同一个user的所有连接加起来不能超过
+    pub user_rate_limits: HashMap<String, RateLimit>,
+    //所有连接的写队列中最多缓存多少字节,0表示不限制
+    pub max_pending_bytes: usize,
+    //每个连接的写队列中最多缓存多少字节,0表示不限制
+    pub max_pending_per_conn: usize,
+    //连续处理这么多条命令或者这么多字节以后主动让出,都是0表示不让出
+    pub yield_msgs: usize,
+    pub yield_bytes: usize,
+}
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_subject_len: 1024,
+            max_subject_tokens: 64,
+            max_subs_per_conn: 100_000,
+            max_total_subs: 10_000_000,
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            write_deadline: Duration::from_secs(10),
+            rate_limit: RateLimit::default(),
+            user_rate_limits: HashMap::new(),
+            max_pending_bytes: 1024 * 1024 * 1024,
+            max_pending_per_conn: 64 * 1024 * 1024,
+            yield_msgs: 128,
+            yield_bytes: 64 * 1024,
+        }
+    }
+}
+/**
+运行中的限制,重新加载配置的时候整个替换掉.
+每个连接每读一批数据取一次快照,所以修改以后已有的连接很快就会按照新的限制来.
+*/
+#[derive(Debug, Default)]
+pub struct SharedLimits(RwLock<Arc<Limits>>);
+impl SharedLimits {
+    pub fn new(limits: Limits) -> Self {
+        Self(RwLock::new(Arc::new(limits)))
+    }
+    pub fn load(&self) -> Arc<Limits> {
+        self.0.read().unwrap().clone()
+    }
+    pub fn store(&self, limits: Limits) {
+        *self.0.write().unwrap() = Arc::new(limits);
+    }
+}
@@ -0,0 +1,193 @@
+/**
+### 日志
+一行一条,形如
+```text
+2020-02-01T08:00:00.123Z INFO  nats_server::client cid=3 addr=127.0.0.1:50001 accepted
+```
+和连接有关的日志都带着cid和addr,用grep就能找出一个连接的所有日志.
+输出到stderr或者log_file指定的文件,文件超过log_size_limit以后轮转,
+当前的文件改名为xxx.1,原来的xxx.1改名为xxx.2,依此类推,最多保留log_max_files个.
+打开trace以后,所有收到的控制行和发出的MSG控制行都会打印出来.
+日志级别,trace以及输出的文件都可以通过重新加载配置在运行中修改.
+*/
+use crate::config::Config;
+use lazy_static::lazy_static;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+lazy_static! {
+    static ref LOGGER: Logger = Logger::default();
+}
+//debug和trace只输出自己的日志,第三方库(比如mio)的太多了
+const OWN_TARGETS: [&str; 3] = ["nats_server", "protocol", "client"];
+
+#[derive(Debug, Default)]
+pub struct Logger {
+    trace: AtomicBool,
+    output: Mutex<Output>,
+}
+#[derive(Debug)]
+enum Output {
+    Stderr,
+    File(RotatingFile),
+}
+impl Default for Output {
+    fn default() -> Self {
+        Output::Stderr
+    }
+}
+impl Logger {
+    //安装全局的logger,只能调用一次,嵌入式使用的时候也可以用自己的logger
+    pub fn init(config: &Config) -> Result<(), Box<dyn Error>> {
+        log::set_logger(&*LOGGER)?;
+        Self::apply(config)
+    }
+    //按照配置修改日志级别,trace以及输出的文件,重新加载配置的时候也调用这里
+    pub fn apply(config: &Config) -> Result<(), Box<dyn Error>> {
+        let level = config.level_filter()?;
+        let output = match config.log_file {
+            Some(ref path) => Output::File(RotatingFile::open(
+                path,
+                config.log_size_limit,
+                config.log_max_files,
+            )?),
+            None => Output::Stderr,
+        };
+        *LOGGER.output.lock().unwrap() = output;
+        LOGGER.trace.store(config.trace, Ordering::Relaxed);
+        log::set_max_level(if config.trace {
+            LevelFilter::Trace
+        } else {
+            level
+        });
+        Ok(())
+    }
+    pub fn is_trace() -> bool {
+        LOGGER.trace.load(Ordering::Relaxed)
+    }
+}
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+            && (metadata.level() <= Level::Info
+                || OWN_TARGETS.iter().any(|t| metadata.target().starts_with(t)))
+    }
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!(
+            "{} {:<5} {} {}\n",
+            humantime::format_rfc3339_millis(SystemTime::now()),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        let mut output = self.output.lock().unwrap();
+        //日志写不出去也没有别的地方可以报告了
+        let _ = match *output {
+            Output::Stderr => std::io::stderr().write_all(line.as_bytes()),
+            Output::File(ref mut f) => f.write(line.as_bytes()),
+        };
+    }
+    fn flush(&self) {}
+}
+/**
+按照大小轮转的日志文件,size_limit为0表示不轮转.
+轮转的时候xxx.(n-1)改名为xxx.n,...,xxx改名为xxx.1,最老的那个被覆盖掉
+*/
+#[derive(Debug)]
+struct RotatingFile {
+    path: String,
+    file: File,
+    size: u64,
+    size_limit: u64,
+    max_files: usize,
+}
+impl RotatingFile {
+    fn open(path: &str, size_limit: u64, max_files: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_string(),
+            file,
+            size,
+            size_limit,
+            max_files,
+        })
+    }
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        if self.size_limit > 0 && self.size > 0 && self.size + buf.len() as u64 > self.size_limit {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+    fn rotate(&mut self) -> std::io::Result<()> {
+        for i in (1..self.max_files).rev() {
+            let from = format!("{}.{}", self.path, i);
+            if Path::new(&from).exists() {
+                std::fs::rename(&from, format!("{}.{}", self.path, i + 1))?;
+            }
+        }
+        //一个都不保留的话直接从头开始写
+        if self.max_files > 0 {
+            std::fs::rename(&self.path, format!("{}.1", self.path))?;
+        } else {
+            std::fs::remove_file(&self.path)?;
+        }
+        *self = Self::open(&self.path, self.size_limit, self.max_files)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("nats-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("nats.log");
+        let path = path.to_str().unwrap();
+        let mut f = RotatingFile::open(path, 100, 2).unwrap();
+        let line = [b'x'; 39];
+        for _ in 0..10 {
+            f.write(&line).unwrap();
+            f.write(b"\n").unwrap();
+        }
+        //每个文件最多两行
+        let len = |p: &str| std::fs::metadata(p).unwrap().len();
+        assert_eq!(len(path), 80);
+        assert_eq!(len(&format!("{}.1", path)), 80);
+        assert_eq!(len(&format!("{}.2", path)), 80);
+        assert!(!Path::new(&format!("{}.3", path)).exists());
+        //重新打开的时候接着写
+        let mut f = RotatingFile::open(path, 100, 0).unwrap();
+        assert_eq!(f.size, 80);
+        f.write(&line).unwrap();
+        assert_eq!(len(path), 39);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_enabled() {
+        let logger = Logger::default();
+        let enabled = |level: Level, target: &str| {
+            logger.enabled(&Metadata::builder().level(level).target(target).build())
+        };
+        log::set_max_level(LevelFilter::Trace);
+        assert!(enabled(Level::Trace, "nats_server::client"));
+        assert!(enabled(Level::Debug, "protocol::codec"));
+        assert!(enabled(Level::Info, "mio::poll"));
+        assert!(!enabled(Level::Debug, "mio::poll"));
+        log::set_max_level(LevelFilter::Off);
+        assert!(!enabled(Level::Error, "nats_server::client"));
+    }
+}
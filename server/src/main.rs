@@ -1,23 +1,120 @@
-#![feature(test)]
-#![feature(hash_raw_entry)]
-
-use crate::server::Server;
-use crate::sublist::TrieSubList;
-use jemallocator::Jemalloc;
+use futures::{select, FutureExt};
+use log::{error, info};
+use nats_server::{AdminRequest, Config, Logger, ServerBuilder, SubListType};
 use std::error::Error;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
 
 #[global_allocator]
-static GLOBAL: Jemalloc = Jemalloc;
+static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
-mod client;
-mod error;
-mod parser;
-mod server;
-mod simple_sublist;
-mod sublist;
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    println!("server start..");
-    let s: Server<TrieSubList> = Server::default();
-    s.start().await
+    //配置文件的路径,收到SIGHUP或者管理接口的RELOAD的时候重新加载
+    let config_path = std::env::var("NATS_CONFIG").ok();
+    let config = load_config(config_path.as_deref())?;
+    Logger::init(&config)?;
+    info!("server start..");
+    let builder = ServerBuilder::new().config(config);
+    //进入lame duck模式的信号,usr1或者usr2
+    let lame_duck_signal = match std::env::var("NATS_LAME_DUCK_SIGNAL")
+        .as_ref()
+        .map(|s| s.as_str())
+    {
+        Ok("usr1") => SignalKind::user_defined1(),
+        Ok("usr2") | Err(_) => SignalKind::user_defined2(),
+        Ok(s) => return Err(format!("unknown lame duck signal {}", s).into()),
+    };
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut lame_duck = signal(lame_duck_signal)?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut handle = builder.start().await?;
+    info!("listen on {}", handle.local_addr());
+    if let Some(addr) = handle.admin_addr() {
+        info!("admin listen on {}", addr);
+    }
+    let mut admin = handle.admin_requests();
+    //SIGTERM和SIGINT马上断开所有的连接,lame duck则是逐步断开,SIGHUP和RELOAD重新加载配置
+    let is_lame_duck = loop {
+        let mut request = None;
+        let stop = select! {
+            r = handle.wait().fuse() => return r,
+            _ = terminate.recv().fuse() => Some(false),
+            _ = interrupt.recv().fuse() => Some(false),
+            _ = lame_duck.recv().fuse() => Some(true),
+            _ = hangup.recv().fuse() => None,
+            r = admin_request(&mut admin).fuse() => {
+                request = Some(r);
+                None
+            }
+        };
+        if let Some(is_lame_duck) = stop {
+            break is_lame_duck;
+        }
+        //加载失败的话继续使用原来的配置
+        let r = match load_config(config_path.as_deref()) {
+            Ok(config) => handle.reload(config).await,
+            Err(e) => Err(e),
+        };
+        match r {
+            Ok(ref changes) if changes.is_empty() => info!("config reloaded, nothing changed"),
+            Ok(_) => {}
+            Err(ref e) => error!("reload config err {}", e),
+        }
+        if let Some(request) = request {
+            request.reply(&r);
+        }
+    };
+    if is_lame_duck {
+        handle.lame_duck().await;
+    } else {
+        handle.shutdown().await;
+    }
+    info!("server stopped");
+    Ok(())
+}
+//没有配置管理接口的话永远等不到
+async fn admin_request(admin: &mut Option<mpsc::Receiver<AdminRequest>>) -> AdminRequest {
+    if let Some(rx) = admin.as_mut() {
+        if let Some(request) = rx.recv().await {
+            return request;
+        }
+    }
+    futures::future::pending().await
+}
+//先读配置文件,环境变量和命令行参数优先
+fn load_config(path: Option<&str>) -> Result<Config, Box<dyn Error>> {
+    let mut config = match path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    //写socket的超时时间,单位毫秒
+    if let Ok(ms) = std::env::var("NATS_WRITE_DEADLINE_MS") {
+        config.limits.write_deadline = Duration::from_millis(ms.parse()?);
+    }
+    //订阅特别多的时候可以用更省内存的compact
+    match std::env::var("NATS_SUBLIST").as_ref().map(|s| s.as_str()) {
+        Ok("compact") => config.sublist = SubListType::Compact,
+        Ok(_) => config.sublist = SubListType::Trie,
+        Err(_) => {}
+    }
+    //queue的负载均衡策略:random,round_robin,least_pending,sticky
+    if let Ok(strategy) = std::env::var("NATS_QUEUE_STRATEGY") {
+        config.queue_strategies.default = strategy.parse()?;
+    }
+    //本地管理接口的地址,比如127.0.0.1:8222
+    if let Ok(addr) = std::env::var("NATS_ADMIN_ADDR") {
+        config.admin_addr = Some(addr);
+    }
+    //lame duck模式下在这段时间内逐步断开所有的连接,单位毫秒
+    if let Ok(ms) = std::env::var("NATS_LAME_DUCK_DURATION_MS") {
+        config.lame_duck_duration = Duration::from_millis(ms.parse()?);
+    }
+    //--trace打印所有收到的控制行和发出的MSG控制行
+    if std::env::args().skip(1).any(|arg| arg == "--trace") {
+        config.trace = true;
+    }
+    Ok(config)
 }
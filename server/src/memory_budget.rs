@@ -0,0 +1,144 @@
+/**
+### 全局的内存预算
+所有连接的写队列中还没有写到socket的字节数加起来不能超过limit.
+每一帧都记在生产它的连接名下,写出去或者被丢掉以后再从名下扣掉.
+超过预算的时候,名下字节数不少于平均值的publisher暂停读socket,直到总量降到预算的3/4以下.
+所有连接名下的字节数加起来就是总量,所以超过预算的时候至少有一个publisher会暂停.
+不能因为自己降到平均值以下就恢复,否则几个publisher轮流恢复,总量还是会一直涨.
+*/
+use serde_derive::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Default)]
+pub struct MemoryBudget {
+    //0表示不限制
+    limit: AtomicUsize,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    //名下有待发送字节的连接个数
+    producers: AtomicUsize,
+}
+//监控用的快照
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct MemoryUsage {
+    pub limit: usize,
+    pub used: usize,
+    pub peak: usize,
+    pub producers: usize,
+}
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit: AtomicUsize::new(limit),
+            ..Default::default()
+        }
+    }
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+    pub fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            limit: self.limit.load(Ordering::Relaxed),
+            used: self.used.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+            producers: self.producers.load(Ordering::Relaxed),
+        }
+    }
+}
+/**
+一个连接在预算中的份额,记录它生产的还没有写出去的字节数
+*/
+#[derive(Debug, Default)]
+pub struct Producer {
+    budget: Arc<MemoryBudget>,
+    pending: AtomicUsize,
+}
+impl Producer {
+    pub fn new(budget: Arc<MemoryBudget>) -> Self {
+        Self {
+            budget,
+            pending: AtomicUsize::new(0),
+        }
+    }
+    pub fn acquire(&self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        if self.pending.fetch_add(n, Ordering::Relaxed) == 0 {
+            self.budget.producers.fetch_add(1, Ordering::Relaxed);
+        }
+        let used = self.budget.used.fetch_add(n, Ordering::Relaxed) + n;
+        self.budget.peak.fetch_max(used, Ordering::Relaxed);
+    }
+    pub fn release(&self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        if self.pending.fetch_sub(n, Ordering::Relaxed) == n {
+            self.budget.producers.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.budget.used.fetch_sub(n, Ordering::Relaxed);
+    }
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+    //总量超过预算,并且自己名下的字节数不少于平均值
+    pub fn should_pause(&self) -> bool {
+        let limit = self.budget.limit.load(Ordering::Relaxed);
+        let used = self.budget.used.load(Ordering::Relaxed);
+        if limit == 0 || used <= limit {
+            return false;
+        }
+        let producers = self.budget.producers.load(Ordering::Relaxed).max(1);
+        let pending = self.pending();
+        pending > 0 && pending >= used / producers
+    }
+    //预算调大或者改成不限制的话也马上恢复
+    pub fn should_resume(&self) -> bool {
+        let limit = self.budget.limit.load(Ordering::Relaxed);
+        limit == 0 || self.budget.used.load(Ordering::Relaxed) <= limit / 4 * 3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_budget() {
+        let budget = Arc::new(MemoryBudget::new(1000));
+        let heavy = Producer::new(budget.clone());
+        let light = Producer::new(budget.clone());
+        heavy.acquire(900);
+        light.acquire(100);
+        assert!(!heavy.should_pause());
+        light.acquire(100);
+        assert_eq!(
+            budget.usage(),
+            MemoryUsage {
+                limit: 1000,
+                used: 1100,
+                peak: 1100,
+                producers: 2,
+            }
+        );
+        //只有超过平均值的暂停
+        assert!(heavy.should_pause());
+        assert!(!light.should_pause());
+        //降到预算的3/4以下才恢复
+        heavy.release(300);
+        assert!(!heavy.should_pause());
+        assert!(!heavy.should_resume());
+        heavy.release(100);
+        assert!(heavy.should_resume());
+        heavy.release(500);
+        light.release(200);
+        assert_eq!(budget.usage().used, 0);
+        assert_eq!(budget.usage().producers, 0);
+        assert_eq!(budget.usage().peak, 1100);
+        //0表示不限制
+        budget.set_limit(0);
+        heavy.acquire(10000);
+        assert!(!heavy.should_pause());
+    }
+}
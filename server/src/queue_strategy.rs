@@ -0,0 +1,201 @@
+/**
+### queue的负载均衡策略
+同一个queue中有多个订阅者的时候,一条消息只能发给其中一个,选哪一个就是这里的策略.
+1. Random: 随机选一个,这是默认的策略
+2. RoundRobin: 轮流选,每个publisher连接针对每个queue各自计数
+3. LeastPending: 选择待发送缓冲区中数据最少的那个,避免把消息堆给已经忙不过来的订阅者
+4. Sticky: 根据消息的key(也就是pub的subject)做一致性hash,相同的key总是发给同一个订阅者,
+   有订阅者加入或者离开的时候,只有少部分key会换到其他订阅者
+可以给server设置一个默认策略,也可以针对某个queue名字单独设置.
+*/
+use crate::simple_sublist::ArcSubscription;
+use rand::{RngCore, SeedableRng};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueStrategy {
+    Random,
+    RoundRobin,
+    LeastPending,
+    Sticky,
+}
+impl Default for QueueStrategy {
+    fn default() -> Self {
+        QueueStrategy::Random
+    }
+}
+impl FromStr for QueueStrategy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(QueueStrategy::Random),
+            "round_robin" => Ok(QueueStrategy::RoundRobin),
+            "least_pending" => Ok(QueueStrategy::LeastPending),
+            "sticky" => Ok(QueueStrategy::Sticky),
+            _ => Err(format!("unknown queue strategy {}", s)),
+        }
+    }
+}
+//配置文件中形如`{"default":"round_robin","queues":{"jobs":"sticky"}}`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueueStrategyConfig {
+    pub default: QueueStrategy,
+    //针对某个queue名字的策略,优先于default
+    pub queues: HashMap<String, QueueStrategy>,
+}
+impl QueueStrategyConfig {
+    pub fn strategy(&self, queue: &str) -> QueueStrategy {
+        self.queues.get(queue).cloned().unwrap_or(self.default)
+    }
+}
+/**
+每个client_task一个,在queue的候选订阅者中挑选一个.
+round robin的计数只在本连接中有效,不需要和其他连接共享,这样就不用加锁.
+*/
+#[derive(Debug)]
+pub struct QueueSelector {
+    config: Arc<QueueStrategyConfig>,
+    rng: rand::rngs::StdRng,
+    next: HashMap<String, usize>,
+}
+impl QueueSelector {
+    pub fn new(config: Arc<QueueStrategyConfig>) -> Self {
+        Self {
+            config,
+            rng: rand::rngs::StdRng::from_entropy(),
+            next: HashMap::new(),
+        }
+    }
+    /**
+    返回选中的订阅者在candidates中的位置,
+    candidates必须属于同一个queue,并且不能为空.
+    key用于Sticky策略
+    */
+    pub fn select(&mut self, candidates: &[&ArcSubscription], key: &str) -> usize {
+        let queue = candidates[0]
+            .queue
+            .as_ref()
+            .map(|q| q.as_str())
+            .unwrap_or("");
+        match self.config.strategy(queue) {
+            QueueStrategy::Random => self.rng.next_u32() as usize % candidates.len(),
+            QueueStrategy::RoundRobin => {
+                let next = self.next.entry(queue.to_string()).or_insert(0);
+                let n = *next % candidates.len();
+                *next = next.wrapping_add(1);
+                n
+            }
+            QueueStrategy::LeastPending => {
+                let mut min = usize::max_value();
+                let mut pos = 0;
+                for (i, sub) in candidates.iter().enumerate() {
+                    let pending = sub.msg_sender.pending_bytes();
+                    if pending < min {
+                        min = pending;
+                        pos = i;
+                    }
+                }
+                pos
+            }
+            QueueStrategy::Sticky => sticky_select(candidates, key),
+        }
+    }
+}
+/*
+rendezvous hash,每个订阅者和key一起算一个分数,分数最高的胜出.
+订阅者的身份用Subscription的地址,只要订阅还在,地址就不会变.
+*/
+fn sticky_select(candidates: &[&ArcSubscription], key: &str) -> usize {
+    let mut max = 0;
+    let mut pos = 0;
+    for (i, sub) in candidates.iter().enumerate() {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (sub.as_ref() as *const _ as usize).hash(&mut hasher);
+        let score = hasher.finish();
+        if i == 0 || score > max {
+            max = score;
+            pos = i;
+        }
+    }
+    pos
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{fill_test_sender, new_test_sender};
+    use crate::simple_sublist::Subscription;
+    fn new_queue_subs(n: usize) -> Vec<ArcSubscription> {
+        (0..n)
+            .map(|_| Arc::new(Subscription::new("foo", Some("q"), "1", new_test_sender())))
+            .collect()
+    }
+    fn new_selector(strategy: QueueStrategy) -> QueueSelector {
+        let mut config = QueueStrategyConfig::default();
+        config.queues.insert("q".into(), strategy);
+        QueueSelector::new(Arc::new(config))
+    }
+    #[test]
+    fn test_strategy_config() {
+        let mut config = QueueStrategyConfig::default();
+        assert_eq!(config.strategy("q"), QueueStrategy::Random);
+        config.default = QueueStrategy::RoundRobin;
+        config.queues.insert("q".into(), QueueStrategy::Sticky);
+        assert_eq!(config.strategy("q"), QueueStrategy::Sticky);
+        assert_eq!(config.strategy("q2"), QueueStrategy::RoundRobin);
+        assert_eq!(
+            "least_pending".parse::<QueueStrategy>().unwrap(),
+            QueueStrategy::LeastPending
+        );
+        assert!("xxx".parse::<QueueStrategy>().is_err());
+    }
+    #[test]
+    fn test_round_robin() {
+        let subs = new_queue_subs(3);
+        let candidates: Vec<_> = subs.iter().collect();
+        let mut s = new_selector(QueueStrategy::RoundRobin);
+        let mut r = Vec::new();
+        for _ in 0..6 {
+            r.push(s.select(candidates.as_slice(), "foo"));
+        }
+        assert_eq!(r, vec![0, 1, 2, 0, 1, 2]);
+    }
+    #[test]
+    fn test_least_pending() {
+        let subs = new_queue_subs(3);
+        fill_test_sender(&subs[0].msg_sender, 100);
+        fill_test_sender(&subs[1].msg_sender, 10);
+        fill_test_sender(&subs[2].msg_sender, 50);
+        let candidates: Vec<_> = subs.iter().collect();
+        let mut s = new_selector(QueueStrategy::LeastPending);
+        assert_eq!(s.select(candidates.as_slice(), "foo"), 1);
+    }
+    #[test]
+    fn test_sticky() {
+        let subs = new_queue_subs(4);
+        let candidates: Vec<_> = subs.iter().collect();
+        let mut s = new_selector(QueueStrategy::Sticky);
+        let mut counts = [0; 4];
+        for i in 0..100 {
+            let key = format!("foo.{}", i);
+            let n = s.select(candidates.as_slice(), &key);
+            assert_eq!(n, s.select(candidates.as_slice(), &key));
+            counts[n] += 1;
+            //去掉一个别的订阅者,不影响这个key的选择
+            let other = (n + 1) % candidates.len();
+            let mut left = candidates.clone();
+            left.remove(other);
+            let n2 = s.select(left.as_slice(), &key);
+            assert!(std::ptr::eq(left[n2], candidates[n]));
+        }
+        assert!(counts.iter().all(|c| *c > 0), "counts={:?}", counts);
+    }
+}
@@ -0,0 +1,123 @@
+/**
+### 发布的速率限制
+令牌桶,每秒补充rate个令牌,最多攒一秒的量,也就是允许一秒的突发.
+消息先处理,再扣令牌,令牌不够的时候记成欠账,
+client_task根据欠账算出要暂停多久,这段时间不读socket,publisher就会被tcp的流控挡住,
+而不是丢掉消息.
+*/
+use serde_derive::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+//0表示不限制
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    pub msgs_per_sec: u64,
+    pub bytes_per_sec: u64,
+}
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+    //扣掉n个令牌,返回需要等多久才能把欠账还上
+    fn consume(&mut self, n: u64, now: Instant) -> Duration {
+        if self.rate == 0 {
+            return Duration::from_secs(0);
+        }
+        let rate = self.rate as f64;
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - n as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+/**
+消息数和字节数各一个令牌桶,每个连接一个,
+CONNECT中指定了user的连接还共享这个user的那一个
+*/
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    msgs: TokenBucket,
+    bytes: TokenBucket,
+}
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            msgs: TokenBucket::new(limit.msgs_per_sec),
+            bytes: TokenBucket::new(limit.bytes_per_sec),
+        }
+    }
+    //重新加载配置的时候调用,限制没变的话已经攒下的令牌和欠账都保留
+    pub fn set_limit(&mut self, limit: RateLimit) {
+        if limit != self.limit {
+            *self = Self::new(limit);
+        }
+    }
+    pub fn consume(&mut self, msgs: u64, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let m = self.msgs.consume(msgs, now);
+        let b = self.bytes.consume(bytes, now);
+        m.max(b)
+    }
+}
+//同一个user的多个连接共享
+pub type SharedRateLimiter = Arc<Mutex<RateLimiter>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut b = TokenBucket::new(100);
+        b.last = start;
+        //一秒的突发
+        assert_eq!(b.consume(100, start), Duration::from_secs(0));
+        assert_eq!(b.consume(50, start), Duration::from_millis(500));
+        //欠账还上以后又可以发了
+        let later = start + Duration::from_millis(600);
+        assert_eq!(b.consume(0, later), Duration::from_secs(0));
+        //最多攒一秒
+        let later = later + Duration::from_secs(10);
+        assert_eq!(b.consume(100, later), Duration::from_secs(0));
+        assert!(b.consume(1, later) > Duration::from_secs(0));
+        //0表示不限制
+        let mut b = TokenBucket::new(0);
+        assert_eq!(b.consume(u64::max_value(), start), Duration::from_secs(0));
+    }
+    #[test]
+    fn test_rate_limiter() {
+        let mut l = RateLimiter::new(RateLimit {
+            msgs_per_sec: 0,
+            bytes_per_sec: 1000,
+        });
+        assert_eq!(l.consume(1, 1000), Duration::from_secs(0));
+        let d = l.consume(1, 500);
+        assert!(d > Duration::from_millis(400) && d <= Duration::from_millis(500));
+        //限制没变,欠账还在
+        l.set_limit(RateLimit {
+            msgs_per_sec: 0,
+            bytes_per_sec: 1000,
+        });
+        assert!(l.consume(1, 0) > Duration::from_millis(400));
+        l.set_limit(RateLimit::default());
+        assert_eq!(l.consume(1000, 1000), Duration::from_secs(0));
+    }
+}
@@ -1,10 +1,28 @@
+use crate::admin::{self, AdminRequest};
 use crate::client::*;
+use crate::compact_sublist::CompactSubList;
+use crate::config::Config;
+use crate::error::{NError, ERROR_KICKED};
+use crate::limits::{Limits, SharedLimits};
+use crate::logger::Logger;
+use crate::memory_budget::{MemoryBudget, MemoryUsage};
+use crate::queue_strategy::QueueStrategyConfig;
+use crate::rate_limit::{RateLimiter, SharedRateLimiter};
 use crate::simple_sublist::SubListTrait;
+use crate::sublist::TrieSubList;
+use futures::{select, FutureExt};
+use log::{error, info, warn};
+use protocol::ServerOp;
+use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
 
 #[derive(Debug, Default)]
 pub struct Server<T: SubListTrait> {
@@ -12,35 +30,1001 @@ pub struct Server<T: SubListTrait> {
 }
 #[derive(Debug, Default)]
 pub struct ServerState<T: SubListTrait> {
-    clients: HashMap<u64, Arc<Mutex<ClientMessageSender>>>,
-    pub sublist: T,
+    //所有活着的连接,client_task开始的时候登记,process_error的时候删除
+    pub clients: HashMap<u64, ClientEntry>,
+    //sublist单独用读写锁保护,pub的时候只需要读锁,不用等ServerState这把锁
+    pub sublist: Arc<RwLock<T>>,
     pub gen_cid: u64,
+    pub queue_strategies: Arc<QueueStrategyConfig>,
+    pub limits: Arc<SharedLimits>,
+    //每个user的限速器,由这个user的连接共享,连接都断开以后就释放了
+    pub user_rate_limiters: HashMap<String, Weak<std::sync::Mutex<RateLimiter>>>,
+    //所有连接的写队列共用的内存预算
+    pub memory_budget: Arc<MemoryBudget>,
+}
+#[derive(Debug)]
+pub struct ClientEntry {
+    pub addr: Option<SocketAddr>,
+    pub start: SystemTime,
+    //CONNECT中指定的名字
+    pub name: Option<String>,
+    pub msg_sender: Arc<ClientMessageSender>,
+}
+/**
+列出连接的时候返回的快照
+*/
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnInfo {
+    pub cid: u64,
+    pub addr: Option<SocketAddr>,
+    pub start: SystemTime,
+    pub name: Option<String>,
+    pub subscriptions: usize,
+    pub in_msgs: u64,
+    pub in_bytes: u64,
+    pub out_msgs: u64,
+    pub out_bytes: u64,
+    pub pending_bytes: usize,
+    pub throttles: u64,
+    pub throttled_us: u64,
+    //这个连接发布的消息还有多少字节在各个订阅者的写队列中
+    pub produced_bytes: usize,
+    pub budget_pauses: u64,
+    pub budget_paused_us: u64,
+    pub yields: u64,
+}
+impl<T: SubListTrait> ServerState<T> {
+    //按照cid排序
+    pub fn connections(&self) -> Vec<ConnInfo> {
+        let mut conns: Vec<_> = self
+            .clients
+            .iter()
+            .map(|(cid, c)| {
+                let stats = &c.msg_sender.stats;
+                ConnInfo {
+                    cid: *cid,
+                    addr: c.addr,
+                    start: c.start,
+                    name: c.name.clone(),
+                    subscriptions: stats.subscriptions.load(Ordering::Relaxed),
+                    in_msgs: stats.in_msgs.load(Ordering::Relaxed),
+                    in_bytes: stats.in_bytes.load(Ordering::Relaxed),
+                    out_msgs: stats.out_msgs.load(Ordering::Relaxed),
+                    out_bytes: stats.out_bytes.load(Ordering::Relaxed),
+                    pending_bytes: c.msg_sender.pending_bytes(),
+                    throttles: stats.throttles.load(Ordering::Relaxed),
+                    throttled_us: stats.throttled_us.load(Ordering::Relaxed),
+                    produced_bytes: c.msg_sender.producer.pending(),
+                    budget_pauses: stats.budget_pauses.load(Ordering::Relaxed),
+                    budget_paused_us: stats.budget_paused_us.load(Ordering::Relaxed),
+                    yields: stats.yields.load(Ordering::Relaxed),
+                }
+            })
+            .collect();
+        conns.sort_by_key(|c| c.cid);
+        conns
+    }
+    //没有配置这个user的限制也要建一个,这样重新加载配置以后马上就能生效
+    pub fn user_rate_limiter(&mut self, user: &str) -> SharedRateLimiter {
+        if let Some(l) = self.user_rate_limiters.get(user).and_then(|l| l.upgrade()) {
+            return l;
+        }
+        //顺便清理掉已经没有连接的user
+        self.user_rate_limiters.retain(|_, l| l.strong_count() > 0);
+        let limit = self.limits.load().user_rate_limits.get(user).copied();
+        let l = Arc::new(std::sync::Mutex::new(RateLimiter::new(
+            limit.unwrap_or_default(),
+        )));
+        self.user_rate_limiters
+            .insert(user.to_string(), Arc::downgrade(&l));
+        l
+    }
+}
+impl<T: SubListTrait + Default> Server<T> {
+    pub fn with_config(config: &Config) -> Self {
+        let mut state = ServerState::<T>::default();
+        state.queue_strategies = Arc::new(config.queue_strategies.clone());
+        state.limits = Arc::new(SharedLimits::new(config.limits.clone()));
+        state.memory_budget = Arc::new(MemoryBudget::new(config.limits.max_pending_bytes));
+        state
+            .sublist
+            .write()
+            .unwrap()
+            .set_cache_size(config.sublist_cache_size);
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
 }
 
-impl<T: SubListTrait + Send + 'static> Server<T> {
-    pub async fn start(self) -> Result<(), Box<dyn Error>> {
-        let addr = "0.0.0.0:4222";
-        let mut listener = TcpListener::bind(addr).await?;
-        //go func(){}
+impl<T: SubListTrait + Send + Sync + 'static> Server<T> {
+    //一直接受新的连接,直到stop被触发或者accept出错
+    pub async fn serve(
+        &self,
+        mut listener: TcpListener,
+        stop: oneshot::Receiver<()>,
+    ) -> std::io::Result<()> {
+        let mut stop = stop.fuse();
         loop {
-            let (conn, _) = listener.accept().await?;
+            let (conn, _) = select! {
+                r = listener.accept().fuse() => r?,
+                _ = stop => return Ok(()),
+            };
             self.new_client(conn).await;
         }
     }
     async fn new_client(&self, conn: TcpStream) {
         let state = self.state.clone();
-        let cid = {
+        let (cid, sublist, limits, budget) = {
             let mut state = state.lock().await;
             state.gen_cid += 1;
-            state.gen_cid
+            (
+                state.gen_cid,
+                state.sublist.clone(),
+                state.limits.clone(),
+                state.memory_budget.clone(),
+            )
+        };
+        //client_task会自己登记到clients中
+        let _c = Client::process_connection(cid, state, sublist, limits, budget, conn);
+    }
+    pub async fn connections(&self) -> Vec<ConnInfo> {
+        self.state.lock().await.connections()
+    }
+    pub async fn memory_usage(&self) -> MemoryUsage {
+        self.state.lock().await.memory_budget.usage()
+    }
+    pub async fn num_connections(&self) -> usize {
+        self.state.lock().await.clients.len()
+    }
+    /**
+    强制断开一个连接,不等排队的消息写完,全部丢掉以后马上关闭socket,
+    然后和其他断开的连接一样由client_task清理订阅并从clients中删除.
+    cid不存在返回false
+    */
+    pub async fn kick(&self, cid: u64) -> bool {
+        //不能拿着state的锁等待,client_task清理的时候也要用这把锁
+        let msg_sender = match self.state.lock().await.clients.get(&cid) {
+            Some(c) => c.msg_sender.clone(),
+            None => return false,
         };
-        let _c = Client::process_connection(cid, state, conn);
-        //        self.state.lock().await.clients.insert(cid, c);
+        info!("{} kicked", msg_sender.ctx);
+        msg_sender.abort(NError::new(ERROR_KICKED));
+        true
+    }
+    /**
+    lame duck模式,先用INFO通知所有的client,
+    然后在duration时间内逐个断开连接,避免所有的client同时重连到其他server.
+    每个连接都会先把已经排队的消息发完再断开.
+    */
+    pub async fn lame_duck(&self, duration: Duration) {
+        let (senders, max_payload) = {
+            let state = self.state.lock().await;
+            let mut senders: Vec<_> = state
+                .clients
+                .iter()
+                .map(|(cid, c)| (*cid, c.msg_sender.clone()))
+                .collect();
+            senders.sort_by_key(|(cid, _)| *cid);
+            (senders, state.limits.load().max_payload)
+        };
+        info!("enter lame duck mode, {} clients", senders.len());
+        for (cid, msg_sender) in senders.iter() {
+            let info = new_server_info(*cid, max_payload, true);
+            if let Err(e) = msg_sender.send_op(ServerOp::Info(info)) {
+                warn!("{} send lame duck info err {}", msg_sender.ctx, e);
+            }
+        }
+        if senders.is_empty() {
+            return;
+        }
+        let interval = duration / senders.len() as u32;
+        for (_, msg_sender) in senders {
+            tokio::time::delay_for(interval).await;
+            msg_sender.close();
+        }
+    }
+    /**
+    把新的配置应用到正在运行的server上,已有的连接不会断开.
+    limits由各个连接自己取快照,max_payload变了的话要重新发INFO告诉client.
+    */
+    pub async fn reload(&self, config: &Config) {
+        let (senders, old_limits) = {
+            let mut state = self.state.lock().await;
+            //已有的连接继续用原来的策略
+            state.queue_strategies = Arc::new(config.queue_strategies.clone());
+            let old_limits = state.limits.load();
+            state.limits.store(config.limits.clone());
+            state
+                .memory_budget
+                .set_limit(config.limits.max_pending_bytes);
+            for (user, l) in state.user_rate_limiters.iter() {
+                if let Some(l) = l.upgrade() {
+                    let limit = config.limits.user_rate_limits.get(user).copied();
+                    l.lock().unwrap().set_limit(limit.unwrap_or_default());
+                }
+            }
+            state
+                .sublist
+                .write()
+                .unwrap()
+                .set_cache_size(config.sublist_cache_size);
+            let senders: Vec<_> = state
+                .clients
+                .iter()
+                .map(|(cid, c)| (*cid, c.msg_sender.clone()))
+                .collect();
+            (senders, old_limits)
+        };
+        let max_payload = config.limits.max_payload;
+        if old_limits.max_payload == max_payload {
+            return;
+        }
+        for (cid, msg_sender) in senders {
+            let info = new_server_info(cid, max_payload, false);
+            if let Err(e) = msg_sender.send_op(ServerOp::Info(info)) {
+                warn!("{} send info err {}", msg_sender.ctx, e);
+            }
+        }
+    }
+    //断开所有的连接,等它们都清理完
+    pub async fn close_clients(&self) {
+        let senders: Vec<_> = {
+            let state = self.state.lock().await;
+            state
+                .clients
+                .values()
+                .map(|c| c.msg_sender.clone())
+                .collect()
+        };
+        for msg_sender in senders {
+            msg_sender.close();
+        }
+        while self.num_connections().await > 0 {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubListType {
+    Trie,
+    //订阅特别多的时候更省内存
+    Compact,
+}
+impl Default for SubListType {
+    fn default() -> Self {
+        SubListType::Trie
+    }
+}
+/**
+嵌入式使用的时候通过ServerBuilder启动server,
+port为0的时候由系统分配一个端口,实际的地址见ServerHandle::local_addr
+*/
+#[derive(Debug, Default)]
+pub struct ServerBuilder {
+    config: Config,
+}
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    //整个替换配置,之后的其他设置会覆盖配置中对应的项
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+    pub fn addr(mut self, addr: &str) -> Self {
+        self.config.addr = addr.to_string();
+        self
+    }
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.config.limits = limits;
+        self
+    }
+    pub fn queue_strategies(mut self, queue_strategies: QueueStrategyConfig) -> Self {
+        self.config.queue_strategies = queue_strategies;
+        self
+    }
+    //本地管理接口的地址,端口为0的时候由系统分配,实际的地址见ServerHandle::admin_addr
+    pub fn admin_addr(mut self, addr: &str) -> Self {
+        self.config.admin_addr = Some(addr.to_string());
+        self
+    }
+    pub fn sublist(mut self, sublist: SubListType) -> Self {
+        self.config.sublist = sublist;
+        self
+    }
+    //lame duck模式下,在这段时间内逐步断开所有的连接
+    pub fn lame_duck_duration(mut self, duration: Duration) -> Self {
+        self.config.lame_duck_duration = duration;
+        self
+    }
+    //绑定端口以后就返回,接受连接在单独的task中进行
+    pub async fn start(self) -> Result<ServerHandle, Box<dyn Error>> {
+        let config = self.config;
+        config.validate()?;
+        let listener = TcpListener::bind((config.addr.as_str(), config.port)).await?;
+        let addr = listener.local_addr()?;
+        let admin = match config.admin_addr {
+            Some(ref admin_addr) => Some(Admin::start(admin_addr.as_str()).await?),
+            None => None,
+        };
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let (server, task) = match config.sublist {
+            SubListType::Trie => {
+                let s = Arc::new(Server::with_config(&config));
+                let task = spawn_serve(s.clone(), listener, stop_rx);
+                (ServerKind::Trie(s), task)
+            }
+            SubListType::Compact => {
+                let s = Arc::new(Server::with_config(&config));
+                let task = spawn_serve(s.clone(), listener, stop_rx);
+                (ServerKind::Compact(s), task)
+            }
+        };
+        Ok(ServerHandle {
+            addr,
+            config,
+            server,
+            admin,
+            stop: Some(stop_tx),
+            task: Some(task),
+        })
+    }
+}
+//管理接口的监听地址,以及交给启动server的程序处理的请求
+#[derive(Debug)]
+struct Admin {
+    addr: SocketAddr,
+    requests: Option<mpsc::Receiver<AdminRequest>>,
+    stop: Option<oneshot::Sender<()>>,
+}
+impl Admin {
+    async fn start(addr: &str) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let (tx, rx) = mpsc::channel(16);
+        let (stop_tx, stop_rx) = oneshot::channel();
+        tokio::spawn(admin::serve(listener, tx, stop_rx));
+        Ok(Self {
+            addr,
+            requests: Some(rx),
+            stop: Some(stop_tx),
+        })
+    }
+}
+fn spawn_serve<T: SubListTrait + Send + Sync + 'static>(
+    server: Arc<Server<T>>,
+    listener: TcpListener,
+    stop: oneshot::Receiver<()>,
+) -> JoinHandle<std::io::Result<()>> {
+    tokio::spawn(async move { server.serve(listener, stop).await })
+}
+//ServerHandle不是泛型的,所以用enum区分不同的sublist
+#[derive(Debug)]
+enum ServerKind {
+    Trie(Arc<Server<TrieSubList>>),
+    Compact(Arc<Server<CompactSubList>>),
+}
+/**
+ServerBuilder::start返回的句柄,
+shutdown会停止接受新的连接,断开所有的连接并清理它们的订阅,
+lame_duck则是先通知client,然后在一段时间内逐步断开.
+*/
+#[derive(Debug)]
+pub struct ServerHandle {
+    addr: SocketAddr,
+    //正在运行的配置,重新加载的时候和它比较
+    config: Config,
+    server: ServerKind,
+    admin: Option<Admin>,
+    stop: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<std::io::Result<()>>>,
+}
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin.as_ref().map(|admin| admin.addr)
+    }
+    /**
+    管理接口收到的请求,只能取一次,没有配置admin_addr的话返回None.
+    取走的程序负责执行,比如RELOAD就重新加载配置文件,调用reload,然后用AdminRequest::reply回复.
+    */
+    pub fn admin_requests(&mut self) -> Option<mpsc::Receiver<AdminRequest>> {
+        self.admin.as_mut().and_then(|admin| admin.requests.take())
+    }
+    /**
+    重新加载配置,收到SIGHUP或者管理请求的时候调用,返回修改了哪些配置.
+    需要重启才能生效的修改会返回错误,这时候正在运行的配置保持不变.
+    */
+    pub async fn reload(&mut self, config: Config) -> Result<Vec<String>, Box<dyn Error>> {
+        let changes = self.config.diff(&config)?;
+        if changes.is_empty() {
+            return Ok(changes);
+        }
+        //先修改日志,打开新的日志文件失败的话什么都不变
+        if config.log_level != self.config.log_level
+            || config.trace != self.config.trace
+            || config.log_file != self.config.log_file
+            || config.log_size_limit != self.config.log_size_limit
+            || config.log_max_files != self.config.log_max_files
+        {
+            Logger::apply(&config)?;
+        }
+        match self.server {
+            ServerKind::Trie(ref s) => s.reload(&config).await,
+            ServerKind::Compact(ref s) => s.reload(&config).await,
+        }
+        for change in changes.iter() {
+            info!("config reloaded, {}", change);
+        }
+        self.config = config;
+        Ok(changes)
+    }
+    pub async fn connections(&self) -> Vec<ConnInfo> {
+        match self.server {
+            ServerKind::Trie(ref s) => s.connections().await,
+            ServerKind::Compact(ref s) => s.connections().await,
+        }
+    }
+    pub async fn memory_usage(&self) -> MemoryUsage {
+        match self.server {
+            ServerKind::Trie(ref s) => s.memory_usage().await,
+            ServerKind::Compact(ref s) => s.memory_usage().await,
+        }
+    }
+    pub async fn kick(&self, cid: u64) -> bool {
+        match self.server {
+            ServerKind::Trie(ref s) => s.kick(cid).await,
+            ServerKind::Compact(ref s) => s.kick(cid).await,
+        }
+    }
+    /**
+    等待接受连接的task退出,只有accept出错或者shutdown的时候才会退出.
+    可以和信号一起select,中途取消的话下次还可以继续等
+    */
+    pub async fn wait(&mut self) -> Result<(), Box<dyn Error>> {
+        let r = match self.task.as_mut() {
+            Some(task) => task.await,
+            None => return Ok(()),
+        };
+        self.task = None;
+        Ok(r??)
+    }
+    pub async fn shutdown(mut self) {
+        self.stop_accept().await;
+        self.close_clients().await;
+    }
+    pub async fn lame_duck(mut self) {
+        self.stop_accept().await;
+        match self.server {
+            ServerKind::Trie(ref s) => s.lame_duck(self.config.lame_duck_duration).await,
+            ServerKind::Compact(ref s) => s.lame_duck(self.config.lame_duck_duration).await,
+        }
+        self.close_clients().await;
+    }
+    async fn stop_accept(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(stop) = self.admin.as_mut().and_then(|admin| admin.stop.take()) {
+            let _ = stop.send(());
+        }
+        //listener随着task一起释放,端口就可以重用了
+        if let Err(e) = self.wait().await {
+            error!("server task err {}", e);
+        }
+    }
+    async fn close_clients(&self) {
+        match self.server {
+            ServerKind::Trie(ref s) => s.close_clients().await,
+            ServerKind::Compact(ref s) => s.close_clients().await,
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::rate_limit::RateLimit;
+    use crate::sublist::TrieSubList;
+    use bytes::Bytes;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     #[test]
     fn test() {}
+    async fn wait_for<F: Fn() -> bool>(f: F) {
+        for _ in 0..100 {
+            if f() {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        panic!("timeout");
+    }
+    #[tokio::test]
+    async fn test_connections_and_kick() {
+        let s: Server<TrieSubList> = Default::default();
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut peers = Vec::new();
+        for _ in 0..2 {
+            let peer = TcpStream::connect(addr).await.unwrap();
+            let (conn, _) = listener.accept().await.unwrap();
+            s.new_client(conn).await;
+            peers.push(peer);
+        }
+        let sublist = s.state.lock().await.sublist.clone();
+        peers[0]
+            .write_all(b"CONNECT {\"name\":\"chat\"}\r\nSUB foo 1\r\nSUB bar 2\r\n")
+            .await
+            .unwrap();
+        wait_for(|| sublist.read().unwrap().count() == 2).await;
+        peers[1]
+            .write_all(b"PUB foo 5\r\nhello\r\nPUB bar 0\r\n\r\n")
+            .await
+            .unwrap();
+        for _ in 0..100 {
+            let conns = s.connections().await;
+            if conns.len() == 2 && conns[0].out_msgs == 2 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        let conns = s.connections().await;
+        assert_eq!(conns.len(), 2);
+        assert_eq!(conns[0].cid, 1);
+        assert_eq!(conns[0].name.as_ref().unwrap(), "chat");
+        assert_eq!(conns[0].addr, Some(peers[0].local_addr().unwrap()));
+        assert_eq!(conns[0].subscriptions, 2);
+        assert_eq!((conns[0].out_msgs, conns[0].out_bytes), (2, 5));
+        assert_eq!((conns[1].in_msgs, conns[1].in_bytes), (2, 5));
+        assert_eq!(conns[1].subscriptions, 0);
+
+        assert!(s.kick(1).await);
+        //被踢掉的连接直接收到EOF
+        let mut buf = Vec::new();
+        peers[0].read_to_end(&mut buf).await.unwrap();
+        for _ in 0..100 {
+            if s.num_connections().await == 1 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(s.num_connections().await, 1);
+        assert_eq!(sublist.read().unwrap().count(), 0);
+        assert!(!s.kick(1).await);
+        assert!(!s.kick(100).await);
+    }
+    //对方不读,写任务卡在writev上的时候也能马上踢掉,排队的帧都从内存预算中扣掉
+    #[tokio::test]
+    async fn test_kick_stalled() {
+        let s: Server<TrieSubList> = Default::default();
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _peer = TcpStream::connect(addr).await.unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+        s.new_client(conn).await;
+        while s.num_connections().await == 0 {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        let msg_sender = s.state.lock().await.clients[&1].msg_sender.clone();
+        //远远超过socket的缓冲区,默认10秒的write deadline之前写不完,但是还没到max_pending_per_conn
+        for _ in 0..32 {
+            let frame = Bytes::from(vec![b'x'; 1024 * 1024]);
+            msg_sender.add_pending(frame.len());
+            msg_sender.send(frame).unwrap();
+        }
+        assert!(s.memory_usage().await.used > 0);
+        assert!(s.kick(1).await);
+        for _ in 0..100 {
+            if s.num_connections().await == 0 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(s.num_connections().await, 0);
+        assert!(msg_sender.is_closed());
+        assert_eq!(s.memory_usage().await.used, 0);
+    }
+    //读到包含expected为止
+    async fn read_until(conn: &mut TcpStream, buf: &mut Vec<u8>, expected: &str) {
+        let mut tmp = [0u8; 1024];
+        while !String::from_utf8_lossy(buf).contains(expected) {
+            let n = conn.read(&mut tmp).await.unwrap();
+            assert!(n > 0, "connection closed");
+            buf.extend_from_slice(&tmp[..n]);
+        }
+    }
+    //监听127.0.0.1上随机的端口
+    fn test_server_builder() -> ServerBuilder {
+        ServerBuilder::new().addr("127.0.0.1").port(0)
+    }
+    async fn start_test_server(limits: Limits) -> ServerHandle {
+        test_server_builder().limits(limits).start().await.unwrap()
+    }
+    //用sid 1订阅subject,收到PONG说明server已经处理完了SUB
+    async fn subscribe(conn: &mut TcpStream, subject: &str) {
+        let sub = format!("SUB {} 1\r\nPING\r\n", subject);
+        conn.write_all(sub.as_bytes()).await.unwrap();
+        read_until(conn, &mut Vec::new(), "PONG\r\n").await;
+    }
+    //同一个进程中启动多个server,互不影响
+    #[tokio::test]
+    async fn test_builder_and_shutdown() {
+        let mut handles = Vec::new();
+        for sublist in [SubListType::Trie, SubListType::Compact].iter() {
+            let handle = test_server_builder()
+                .sublist(*sublist)
+                .start()
+                .await
+                .unwrap();
+            assert_ne!(handle.local_addr().port(), 0);
+            handles.push(handle);
+        }
+        assert_ne!(handles[0].local_addr(), handles[1].local_addr());
+        for handle in handles {
+            let addr = handle.local_addr();
+            let mut sub = TcpStream::connect(addr).await.unwrap();
+            let mut publisher = TcpStream::connect(addr).await.unwrap();
+            subscribe(&mut sub, "foo").await;
+            let mut buf = Vec::new();
+            publisher
+                .write_all(b"PUB foo 5\r\nhello\r\n")
+                .await
+                .unwrap();
+            read_until(&mut sub, &mut buf, "MSG foo 1 5\r\nhello\r\n").await;
+            assert_eq!(handle.connections().await.len(), 2);
+
+            handle.shutdown().await;
+            //连接都被断开,端口也不再接受新的连接
+            let mut rest = Vec::new();
+            sub.read_to_end(&mut rest).await.unwrap();
+            publisher.read_to_end(&mut rest).await.unwrap();
+            assert!(TcpStream::connect(addr).await.is_err());
+        }
+    }
+    //重新加载配置,已有的连接不断开,马上按照新的限制来
+    #[tokio::test]
+    async fn test_reload() {
+        let mut handle = start_test_server(Limits::default()).await;
+        let addr = handle.local_addr();
+        let mut sub = TcpStream::connect(addr).await.unwrap();
+        let mut publisher = TcpStream::connect(addr).await.unwrap();
+        subscribe(&mut sub, "foo").await;
+        let mut buf = Vec::new();
+
+        //需要重启的修改被拒绝,什么都不变
+        let mut config = handle.config().clone();
+        config.port = 4333;
+        config.limits.max_payload = 8;
+        assert!(handle.reload(config).await.is_err());
+        assert_eq!(handle.config().limits.max_payload, 1024 * 1024);
+
+        let mut config = handle.config().clone();
+        config.limits.max_payload = 8;
+        config.sublist_cache_size = 16;
+        let changes = handle.reload(config.clone()).await.unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(handle.config(), &config);
+        assert!(handle.reload(config).await.unwrap().is_empty());
+        //已有的连接收到新的INFO
+        read_until(&mut sub, &mut buf, "\"max_payload\":8").await;
+        sub.write_all(b"PUB foo 5\r\nhello\r\nPING\r\n")
+            .await
+            .unwrap();
+        read_until(&mut sub, &mut buf, "MSG foo 1 5\r\nhello\r\n").await;
+        //超过新的max_payload,连接被断开
+        publisher
+            .write_all(b"PUB foo 9\r\nhello foo\r\n")
+            .await
+            .unwrap();
+        let mut rest = Vec::new();
+        publisher.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(handle.connections().await.len(), 1);
+        handle.shutdown().await;
+    }
+    //管理接口的RELOAD交给启动server的程序执行,回复修改了哪些配置或者失败的原因
+    #[tokio::test]
+    async fn test_admin_reload() {
+        use crate::admin::AdminCommand;
+        let mut handle = test_server_builder()
+            .admin_addr("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let admin_addr = handle.admin_addr().unwrap();
+        let mut requests = handle.admin_requests().unwrap();
+        assert!(handle.admin_requests().is_none());
+        let mut config = handle.config().clone();
+        config.limits.max_payload = 2048;
+        let mut restart = config.clone();
+        restart.port = 4333;
+        let mut configs = vec![config, restart].into_iter();
+        //相当于main中收到SIGHUP以后的处理
+        let serve = async {
+            for config in &mut configs {
+                let request = requests.recv().await.unwrap();
+                assert_eq!(request.command, AdminCommand::Reload);
+                let r = handle.reload(config).await;
+                request.reply(&r);
+            }
+        };
+        let client = async {
+            let mut conn = TcpStream::connect(admin_addr).await.unwrap();
+            conn.write_all(b"RELOAD\r\nfoo\r\n\r\nreload\r\n")
+                .await
+                .unwrap();
+            let mut buf = Vec::new();
+            read_until(&mut conn, &mut buf, "requires restart").await;
+            String::from_utf8(buf).unwrap()
+        };
+        let (_, replies) = tokio::join!(serve, client);
+        let replies: Vec<_> = replies.lines().collect();
+        assert_eq!(
+            replies[0],
+            r#"{"changes":["max_payload: 1048576 -> 2048"]}"#
+        );
+        assert_eq!(replies[1], r#"{"error":"unknown command foo"}"#);
+        assert!(replies[2].starts_with(r#"{"error":"listen address"#));
+        assert_eq!(handle.config().limits.max_payload, 2048);
+        handle.shutdown().await;
+    }
+    //超过速率限制的连接暂停读,消息不会丢,同一个user的连接共享限制
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let mut limits = Limits::default();
+        let alice = RateLimit {
+            msgs_per_sec: 200,
+            bytes_per_sec: 0,
+        };
+        limits.user_rate_limits.insert("alice".to_string(), alice);
+        let mut handle = start_test_server(limits).await;
+        let addr = handle.local_addr();
+        let mut conns = Vec::new();
+        for _ in 0..3 {
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            let mut buf = Vec::new();
+            read_until(&mut conn, &mut buf, "INFO").await;
+            conns.push(conn);
+        }
+        let mut sub = conns.remove(0);
+        subscribe(&mut sub, "foo").await;
+        let mut buf = Vec::new();
+        let pubs = |n: usize| b"PUB foo 1\r\nx\r\n".repeat(n);
+        //第一个连接正好用完alice一秒的量
+        let mut data = b"CONNECT {\"user\":\"alice\"}\r\n".to_vec();
+        data.extend_from_slice(&pubs(200));
+        data.extend_from_slice(b"PING\r\n");
+        conns[0].write_all(&data).await.unwrap();
+        read_until(&mut conns[0], &mut Vec::new(), "PONG\r\n").await;
+        //第二个连接要等alice的欠账还上才能继续读
+        let mut data = b"CONNECT {\"user\":\"alice\"}\r\n".to_vec();
+        data.extend_from_slice(&pubs(100));
+        let start = std::time::Instant::now();
+        conns[1].write_all(&data).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        conns[1].write_all(b"PING\r\n").await.unwrap();
+        read_until(&mut conns[1], &mut Vec::new(), "PONG\r\n").await;
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        let msg = "MSG foo 1 1\r\nx\r\n";
+        let mut tmp = [0u8; 4096];
+        while String::from_utf8_lossy(&buf).matches(msg).count() < 300 {
+            let n = sub.read(&mut tmp).await.unwrap();
+            assert!(n > 0);
+            buf.extend_from_slice(&tmp[..n]);
+        }
+        let infos = handle.connections().await;
+        assert_eq!(infos[1].throttles, 0);
+        assert!(infos[2].throttles > 0);
+        assert!(infos[2].throttled_us >= 400_000);
+
+        //每个连接的限制在运行中修改
+        let mut config = handle.config().clone();
+        config.limits.rate_limit.msgs_per_sec = 100;
+        handle.reload(config).await.unwrap();
+        let start = std::time::Instant::now();
+        sub.write_all(&pubs(150)).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        sub.write_all(b"PING\r\n").await.unwrap();
+        read_until(&mut sub, &mut Vec::new(), "PONG\r\n").await;
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert!(handle.connections().await[0].throttles > 0);
+        handle.shutdown().await;
+    }
+    //订阅者很慢,publisher很快,写队列的总量也不能超过预算太多,消息一条都不能丢
+    #[tokio::test]
+    async fn test_memory_budget() {
+        const BUDGET: usize = 256 * 1024;
+        const MSGS: usize = 200;
+        let payload = vec![b'x'; 16 * 1024];
+        let handle = start_test_server(Limits {
+            max_pending_bytes: BUDGET,
+            ..Default::default()
+        })
+        .await;
+        let addr = handle.local_addr();
+        let mut sub = TcpStream::connect(addr).await.unwrap();
+        subscribe(&mut sub, "foo").await;
+        let mut data = format!("PUB foo {}\r\n", payload.len()).into_bytes();
+        data.extend_from_slice(&payload);
+        data.extend_from_slice(b"\r\n");
+        let data = data.repeat(MSGS);
+        let mut pubs = Vec::new();
+        for _ in 0..2 {
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            read_until(&mut conn, &mut Vec::new(), "INFO").await;
+            let data = data.clone();
+            pubs.push(tokio::spawn(async move {
+                conn.write_all(&data).await.unwrap();
+                conn
+            }));
+        }
+        let msg_len = format!("MSG foo 1 {}\r\n", payload.len()).len() + payload.len() + 2;
+        let total = msg_len * MSGS * 2;
+        let mut received = 0;
+        let mut tmp = vec![0u8; 64 * 1024];
+        while received < total {
+            let n = sub.read(&mut tmp).await.unwrap();
+            assert!(n > 0);
+            received += n;
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(received, total);
+        for p in pubs {
+            p.await.unwrap();
+        }
+        let usage = handle.memory_usage().await;
+        assert_eq!(usage.limit, BUDGET);
+        assert_eq!(usage.used, 0);
+        assert!(usage.peak <= BUDGET * 2, "{:?}", usage);
+        let infos = handle.connections().await;
+        assert!(infos[1].budget_pauses + infos[2].budget_pauses > 0);
+        handle.shutdown().await;
+    }
+    //全局的预算还很宽裕,一个订阅者的写队列太长的时候publisher也要暂停,而不是把订阅者作为慢消费者断开
+    #[tokio::test]
+    async fn test_slow_subscriber_backpressure() {
+        const MAX_PENDING: usize = 256 * 1024;
+        const MSGS: usize = 400;
+        let payload = vec![b'x'; 16 * 1024];
+        let handle = start_test_server(Limits {
+            max_pending_per_conn: MAX_PENDING,
+            ..Default::default()
+        })
+        .await;
+        let addr = handle.local_addr();
+        let mut sub = TcpStream::connect(addr).await.unwrap();
+        subscribe(&mut sub, "foo").await;
+        let mut data = format!("PUB foo {}\r\n", payload.len()).into_bytes();
+        data.extend_from_slice(&payload);
+        data.extend_from_slice(b"\r\n");
+        let data = data.repeat(MSGS);
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        read_until(&mut conn, &mut Vec::new(), "INFO").await;
+        let publisher = tokio::spawn(async move {
+            conn.write_all(&data).await.unwrap();
+            conn
+        });
+        let msg_len = format!("MSG foo 1 {}\r\n", payload.len()).len() + payload.len() + 2;
+        let total = msg_len * MSGS;
+        let mut received = 0;
+        let mut tmp = vec![0u8; 64 * 1024];
+        while received < total {
+            let n = sub.read(&mut tmp).await.unwrap();
+            assert!(n > 0, "slow subscriber disconnected");
+            received += n;
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(received, total);
+        publisher.await.unwrap();
+        let infos = handle.connections().await;
+        assert_eq!(infos.len(), 2);
+        assert!(infos[1].budget_pauses > 0);
+        handle.shutdown().await;
+    }
+    /*
+    一个连接不停地批量发布小消息,另一个连接低频地发布,
+    低频的那个从发布到收到自己的消息的延迟不能被批量的拖住.
+    测试用的是单线程的runtime,并且是debug编译,延迟的绝对值没有意义,
+    所以只检查批量的连接确实让出了,以及往返延迟的中位数有一个宽松的上限,
+    让出的话中位数是几十ms,不让出每次往返都要一秒以上.
+    release下的p99用bench的--latency-probes测,见bench/run-latency.sh.
+    */
+    #[tokio::test]
+    async fn test_fair_scheduling() {
+        let handle = start_test_server(Limits::default()).await;
+        let addr = handle.local_addr();
+        let mut bulk_sub = TcpStream::connect(addr).await.unwrap();
+        subscribe(&mut bulk_sub, "bulk").await;
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let s = stop.clone();
+        let drain = tokio::spawn(async move {
+            let mut tmp = vec![0u8; 64 * 1024];
+            while !s.load(Ordering::Relaxed) {
+                match bulk_sub.read(&mut tmp).await {
+                    Ok(n) if n > 0 => {}
+                    _ => break,
+                }
+            }
+        });
+        let mut bulk = TcpStream::connect(addr).await.unwrap();
+        read_until(&mut bulk, &mut Vec::new(), "INFO").await;
+        let s = stop.clone();
+        let flood = tokio::spawn(async move {
+            let data = b"PUB bulk 16\r\n0123456789abcdef\r\n".repeat(16 * 1024);
+            while !s.load(Ordering::Relaxed) {
+                if bulk.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        subscribe(&mut conn, "ping").await;
+        //等批量的跑起来
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        let mut latencies = Vec::new();
+        for _ in 0..50 {
+            let mut buf = Vec::new();
+            let start = std::time::Instant::now();
+            conn.write_all(b"PUB ping 1\r\nx\r\n").await.unwrap();
+            read_until(&mut conn, &mut buf, "MSG ping 1 1\r\nx\r\n").await;
+            latencies.push(start.elapsed());
+            tokio::time::delay_for(Duration::from_millis(2)).await;
+        }
+        latencies.sort();
+        let p50 = latencies[latencies.len() / 2];
+        assert!(p50 < Duration::from_millis(500), "p50 {:?}", p50);
+        let infos = handle.connections().await;
+        assert!(infos[1].yields > 0);
+        //低频的连接每次只有一条命令,从来不用让出
+        assert_eq!(infos[2].yields, 0);
+        stop.store(true, Ordering::Relaxed);
+        handle.shutdown().await;
+        let _ = flood.await;
+        let _ = drain.await;
+    }
+    //lame duck模式下client先收到通知,之前的消息都能收到,然后在duration内逐个断开
+    #[tokio::test]
+    async fn test_lame_duck() {
+        let duration = Duration::from_millis(300);
+        let handle = test_server_builder()
+            .lame_duck_duration(duration)
+            .start()
+            .await
+            .unwrap();
+        let addr = handle.local_addr();
+        let mut peers = Vec::new();
+        for _ in 0..3 {
+            let mut peer = TcpStream::connect(addr).await.unwrap();
+            subscribe(&mut peer, "foo").await;
+            peers.push(peer);
+        }
+        peers[0].write_all(b"PUB foo 5\r\nhello\r\n").await.unwrap();
+        for peer in peers.iter_mut() {
+            let mut buf = Vec::new();
+            read_until(peer, &mut buf, "MSG foo 1 5\r\nhello\r\n").await;
+        }
+        let start = std::time::Instant::now();
+        handle.lame_duck().await;
+        assert!(start.elapsed() >= duration);
+        assert!(TcpStream::connect(addr).await.is_err());
+        for peer in peers.iter_mut() {
+            let mut buf = Vec::new();
+            peer.read_to_end(&mut buf).await.unwrap();
+            let s = String::from_utf8(buf).unwrap();
+            assert!(s.starts_with("INFO "));
+            assert!(s.contains("\"ldm\":true"));
+        }
+    }
 }
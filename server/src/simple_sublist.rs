@@ -1,19 +1,22 @@
 use crate::client::ClientMessageSender;
-use crate::error::{NError, Result, ERROR_SUBSCRIBTION_NOT_FOUND};
+use crate::error::{NError, Result, ERROR_INVALID_SUBJECT, ERROR_SUBSCRIBTION_NOT_FOUND};
+use crate::sublist::{is_subset_match, is_valid_subject, match_literal};
 use bitflags::_core::cmp::Ordering;
+use serde_derive::Serialize;
 use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
 
 /**
 为了讲解方便,考虑到Trie的实现以及Cache的实现都是很琐碎,
-我这里专门实现一个简单的订阅关系查找,不支持*和>这两种模糊匹配.
-这样就是简单的字符串查找了. 使用map即可.
+我这里专门实现一个简单的订阅关系查找,订阅按照主题放在map中,
+查找的时候逐个用match_literal比较,所以也支持*和>这两种模糊匹配,只是慢一些.
+它的结果应该和Trie树完全一致,所以可以用来验证Trie树的正确性.
 但是为了后续的扩展性呢,我会定义SubListTrait,这样方便后续实现Trie树
 */
 #[derive(Debug)]
 pub struct Subscription {
-    pub msg_sender: Arc<Mutex<ClientMessageSender>>,
+    pub msg_sender: Arc<ClientMessageSender>,
     pub subject: String,
     pub queue: Option<String>,
     pub sid: String,
@@ -23,7 +26,7 @@ impl Subscription {
         subject: &str,
         queue: Option<&str>,
         sid: &str,
-        msg_sender: Arc<Mutex<ClientMessageSender>>,
+        msg_sender: Arc<ClientMessageSender>,
     ) -> Self {
         Self {
             subject: subject.to_string(),
@@ -50,6 +53,10 @@ impl SubResult {
     fn is_empty(&self) -> bool {
         self.psubs.len() == 0 && self.qsubs.len() == 0
     }
+    //一条消息实际会推送给多少个订阅,每个queue只推送一个
+    pub fn fanout(&self) -> usize {
+        self.psubs.len() + self.qsubs.len()
+    }
 }
 pub type ArcSubscription = Arc<Subscription>;
 /*
@@ -80,72 +87,205 @@ impl std::cmp::Ord for ArcSubscriptionWrapper {
     }
 }
 pub type ArcSubResult = Arc<SubResult>;
+/**
+insert和remove需要独占,match_subject只需要共享引用,
+这样server就可以用读写锁保护sublist,多个publisher同时查找的时候互不影响.
+*/
 pub trait SubListTrait {
     fn insert(&mut self, sub: ArcSubscription) -> Result<()>;
     fn remove(&mut self, sub: ArcSubscription) -> Result<()>;
-    fn match_subject(&mut self, subject: &str) -> ArcSubResult;
+    fn match_subject(&self, subject: &str) -> ArcSubResult;
+    //每次insert或者remove成功都会加1,外部的缓存据此判断是否过期
+    fn generation(&self) -> u64;
+    //当前订阅的总数
+    fn count(&self) -> usize;
+    //树的深度,也就是最长的订阅主题有几段
+    fn num_levels(&self) -> usize;
+    //树中节点的个数
+    fn num_nodes(&self) -> usize;
+    //所有的订阅
+    fn subscriptions(&self) -> Vec<ArcSubscription>;
+    /**
+    和match_subject反过来,找出所有被pattern覆盖的订阅.
+    比如foo.*可以找到foo.bar和foo.*的订阅,但是找不到foo.>的订阅,
+    因为foo.>的范围比foo.*更大
+    */
+    fn reverse_match(&self, pattern: &str) -> Vec<ArcSubscription>;
+    fn stats(&self) -> SubListStats;
+    //修改match结果cache的大小,多出来的项按照lru淘汰,没有cache的实现什么都不用做
+    fn set_cache_size(&mut self, _cache_size: usize) {}
+}
+/**
+给监控使用的统计信息
+*/
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SubListStats {
+    pub num_subscriptions: usize,
+    pub num_cache: usize,
+    pub num_inserts: u64,
+    pub num_removes: u64,
+    pub num_matches: u64,
+    pub cache_hit_rate: f64,
+    pub max_fanout: u64,
+    pub avg_fanout: f64,
+}
+/*
+match_subject只有&self,所以计数都用原子变量
+*/
+#[derive(Debug, Default)]
+pub(crate) struct SubListCounters {
+    inserts: AtomicU64,
+    removes: AtomicU64,
+    matches: AtomicU64,
+    cache_hits: AtomicU64,
+    max_fanout: AtomicU64,
+    total_fanout: AtomicU64,
+}
+impl SubListCounters {
+    pub(crate) fn record_insert(&self) {
+        self.inserts.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+    pub(crate) fn record_remove(&self) {
+        self.removes.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+    pub(crate) fn record_match(&self, r: &SubResult, cache_hit: bool) {
+        self.matches.fetch_add(1, AtomicOrdering::Relaxed);
+        if cache_hit {
+            self.cache_hits.fetch_add(1, AtomicOrdering::Relaxed);
+        }
+        let fanout = r.fanout() as u64;
+        self.total_fanout.fetch_add(fanout, AtomicOrdering::Relaxed);
+        let mut max = self.max_fanout.load(AtomicOrdering::Relaxed);
+        while fanout > max {
+            match self.max_fanout.compare_exchange_weak(
+                max,
+                fanout,
+                AtomicOrdering::Relaxed,
+                AtomicOrdering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => max = current,
+            }
+        }
+    }
+    pub(crate) fn stats(&self, num_subscriptions: usize, num_cache: usize) -> SubListStats {
+        let matches = self.matches.load(AtomicOrdering::Relaxed);
+        let mut stats = SubListStats {
+            num_subscriptions,
+            num_cache,
+            num_inserts: self.inserts.load(AtomicOrdering::Relaxed),
+            num_removes: self.removes.load(AtomicOrdering::Relaxed),
+            num_matches: matches,
+            max_fanout: self.max_fanout.load(AtomicOrdering::Relaxed),
+            ..Default::default()
+        };
+        if matches > 0 {
+            stats.cache_hit_rate =
+                self.cache_hits.load(AtomicOrdering::Relaxed) as f64 / matches as f64;
+            stats.avg_fanout =
+                self.total_fanout.load(AtomicOrdering::Relaxed) as f64 / matches as f64;
+        }
+        stats
+    }
 }
 #[derive(Debug, Default)]
 pub struct SimpleSubList {
     subs: HashMap<String, BTreeSet<ArcSubscriptionWrapper>>,
     qsubs: HashMap<String, HashMap<String, BTreeSet<ArcSubscriptionWrapper>>>,
+    generation: u64,
+    count: usize,
+    counters: SubListCounters,
+}
+impl SimpleSubList {
+    fn for_each_sub<F: FnMut(&ArcSubscription)>(&self, mut f: F) {
+        for subs in self.subs.values() {
+            subs.iter().for_each(|s| f(&s.0));
+        }
+        for qsubs in self.qsubs.values() {
+            for subs in qsubs.values() {
+                subs.iter().for_each(|s| f(&s.0));
+            }
+        }
+    }
 }
 
 impl SubListTrait for SimpleSubList {
     fn insert(&mut self, sub: Arc<Subscription>) -> Result<()> {
+        if !is_valid_subject(sub.subject.as_str()) {
+            return Err(NError::new(ERROR_INVALID_SUBJECT));
+        }
         if let Some(ref q) = sub.queue {
             let entry = self
                 .qsubs
                 .entry(sub.subject.clone())
                 .or_insert(Default::default());
             let queue = entry.entry(q.clone()).or_insert(Default::default());
-            queue.insert(ArcSubscriptionWrapper(sub));
+            if queue.insert(ArcSubscriptionWrapper(sub)) {
+                self.count += 1;
+            }
         } else {
             let subs = self
                 .subs
                 .entry(sub.subject.clone())
                 .or_insert(Default::default());
-            subs.insert(ArcSubscriptionWrapper(sub));
+            if subs.insert(ArcSubscriptionWrapper(sub)) {
+                self.count += 1;
+            }
         }
+        self.generation += 1;
+        self.counters.record_insert();
         Ok(())
     }
 
     fn remove(&mut self, sub: Arc<Subscription>) -> Result<()> {
+        let mut removed = false;
         if let Some(ref q) = sub.queue {
             if let Some(subs) = self.qsubs.get_mut(&sub.subject) {
                 if let Some(qsubs) = subs.get_mut(q) {
-                    qsubs.remove(&ArcSubscriptionWrapper(sub.clone()));
+                    removed = qsubs.remove(&ArcSubscriptionWrapper(sub.clone()));
                     if qsubs.is_empty() {
                         subs.remove(q);
                     }
-                } else {
-                    return Err(NError::new(ERROR_SUBSCRIBTION_NOT_FOUND));
                 }
                 if subs.is_empty() {
                     self.qsubs.remove(&sub.subject);
                 }
-            } else {
-                return Err(NError::new(ERROR_SUBSCRIBTION_NOT_FOUND));
             }
         } else {
             if let Some(subs) = self.subs.get_mut(&sub.subject) {
-                subs.remove(&ArcSubscriptionWrapper(sub.clone()));
+                removed = subs.remove(&ArcSubscriptionWrapper(sub.clone()));
                 if subs.is_empty() {
                     self.subs.remove(&sub.subject);
                 }
             }
         }
+        //和Trie树保持一致,不存在的订阅返回错误
+        if !removed {
+            return Err(NError::new(ERROR_SUBSCRIBTION_NOT_FOUND));
+        }
+        self.count -= 1;
+        self.generation += 1;
+        self.counters.record_remove();
         Ok(())
     }
 
-    fn match_subject(&mut self, subject: &str) -> ArcSubResult {
+    //逐个比较所有订阅的主题,不同主题下即使queue相同也是不同的组,这和Trie树是一样的
+    fn match_subject(&self, subject: &str) -> ArcSubResult {
         let mut r = SubResult::default();
-        if let Some(subs) = self.subs.get(subject) {
+        for (_, subs) in self
+            .subs
+            .iter()
+            .filter(|(pattern, _)| match_literal(subject, pattern))
+        {
             for s in subs {
                 r.psubs.push(s.0.clone());
             }
         }
-        if let Some(qsubs) = self.qsubs.get(subject) {
+        for (_, qsubs) in self
+            .qsubs
+            .iter()
+            .filter(|(pattern, _)| match_literal(subject, pattern))
+        {
             for (_, qsub) in qsubs {
                 let mut v = Vec::with_capacity(qsub.len());
                 for s in qsub {
@@ -154,14 +294,57 @@ impl SubListTrait for SimpleSubList {
                 r.qsubs.push(v);
             }
         }
+        self.counters.record_match(&r, false);
         Arc::new(r)
     }
+
+    fn generation(&self) -> u64 {
+        self.generation
+    }
+    fn count(&self) -> usize {
+        self.count
+    }
+    //没有树,可以认为只有一层,每个不同的主题是一个节点
+    fn num_levels(&self) -> usize {
+        if self.subs.is_empty() && self.qsubs.is_empty() {
+            0
+        } else {
+            1
+        }
+    }
+    fn num_nodes(&self) -> usize {
+        let mut n = self.subs.len();
+        n += self
+            .qsubs
+            .keys()
+            .filter(|subject| !self.subs.contains_key(*subject))
+            .count();
+        n
+    }
+    fn subscriptions(&self) -> Vec<ArcSubscription> {
+        let mut v = Vec::with_capacity(self.count);
+        self.for_each_sub(|s| v.push(s.clone()));
+        v
+    }
+    fn reverse_match(&self, pattern: &str) -> Vec<ArcSubscription> {
+        let mut v = Vec::new();
+        self.for_each_sub(|s| {
+            if is_subset_match(s.subject.as_str(), pattern) {
+                v.push(s.clone());
+            }
+        });
+        v
+    }
+    fn stats(&self) -> SubListStats {
+        self.counters.stats(self.count, 0)
+    }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use crate::client::new_test_tcp_writer;
+    use rand::{Rng, SeedableRng};
 
     #[test]
     fn test_match() {
@@ -257,4 +440,154 @@ mod tests {
         assert_eq!(r.psubs.len(), 0);
         assert_eq!(r.qsubs.len(), 0);
     }
+    #[test]
+    fn test_match_wildcard() {
+        let mut sl = SimpleSubList::default();
+        let lsub = Arc::new(Subscription::new("a.b.c", None, "1", new_test_tcp_writer()));
+        let psub = Arc::new(Subscription::new("a.*.c", None, "2", new_test_tcp_writer()));
+        let fsub = Arc::new(Subscription::new("a.>", Some("q"), "3", new_test_tcp_writer()));
+        sl.insert(lsub.clone()).unwrap();
+        sl.insert(psub.clone()).unwrap();
+        sl.insert(fsub.clone()).unwrap();
+        assert!(sl
+            .insert(Arc::new(Subscription::new("a.>.c", None, "4", new_test_tcp_writer())))
+            .is_err());
+        let r = sl.match_subject("a.b.c");
+        assert_eq!(r.psubs.len(), 2);
+        assert_eq!(r.qsubs.len(), 1);
+        let r = sl.match_subject("a.d.c");
+        assert_eq!(r.psubs.len(), 1);
+        assert!(Arc::ptr_eq(&r.psubs[0], &psub));
+        let r = sl.match_subject("a.d");
+        assert_eq!(r.psubs.len(), 0);
+        assert_eq!(r.qsubs.len(), 1);
+        let r = sl.match_subject("a");
+        assert!(r.is_empty());
+        sl.remove(fsub.clone()).unwrap();
+        assert!(sl.remove(fsub).is_err());
+    }
+    /*
+    随机的订阅,取消订阅以及查找,SimpleSubList和TrieSubList的结果必须完全一致.
+    比较的时候忽略顺序,只比较订阅的地址
+    */
+    pub(crate) fn sorted_result(r: &SubResult) -> (Vec<usize>, Vec<Vec<usize>>) {
+        let addr = |s: &ArcSubscription| s.as_ref() as *const Subscription as usize;
+        let mut psubs: Vec<_> = r.psubs.iter().map(addr).collect();
+        psubs.sort();
+        let mut qsubs: Vec<Vec<_>> = r
+            .qsubs
+            .iter()
+            .map(|v| {
+                let mut v: Vec<_> = v.iter().map(addr).collect();
+                v.sort();
+                v
+            })
+            .collect();
+        qsubs.sort();
+        (psubs, qsubs)
+    }
+    pub(crate) fn random_subject(rng: &mut rand::rngs::StdRng, wildcard: bool) -> String {
+        let tokens = if wildcard {
+            &["a", "b", "c", "*", ">"][..]
+        } else {
+            &["a", "b", "c"][..]
+        };
+        let n = rng.gen_range(1, 4);
+        let mut v = Vec::with_capacity(n);
+        for i in 0..n {
+            let t = tokens[rng.gen_range(0, tokens.len())];
+            v.push(t);
+            //>只能出现在最后
+            if t == ">" || i == n - 1 {
+                break;
+            }
+        }
+        v.join(".")
+    }
+    #[test]
+    fn test_differential_with_trie() {
+        use crate::sublist::TrieSubList;
+        for seed in 0..20 {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            let mut simple = SimpleSubList::default();
+            let mut trie = TrieSubList::new();
+            let mut subs: Vec<ArcSubscription> = Vec::new();
+            for _ in 0..500 {
+                match rng.gen_range(0, 3) {
+                    0 => {
+                        let subject = random_subject(&mut rng, true);
+                        let queue = ["", "q1", "q2"][rng.gen_range(0, 3)];
+                        let queue = if queue.is_empty() { None } else { Some(queue) };
+                        let sub = Arc::new(Subscription::new(
+                            subject.as_str(),
+                            queue,
+                            "1",
+                            new_test_tcp_writer(),
+                        ));
+                        assert!(simple.insert(sub.clone()).is_ok());
+                        assert!(trie.insert(sub.clone()).is_ok());
+                        subs.push(sub);
+                    }
+                    1 if !subs.is_empty() => {
+                        let sub = subs.swap_remove(rng.gen_range(0, subs.len()));
+                        assert!(simple.remove(sub.clone()).is_ok());
+                        assert!(trie.remove(sub.clone()).is_ok());
+                        assert!(simple.remove(sub.clone()).is_err());
+                        assert!(trie.remove(sub).is_err());
+                    }
+                    _ => {
+                        let subject = random_subject(&mut rng, false);
+                        let r1 = simple.match_subject(subject.as_str());
+                        let r2 = trie.match_subject(subject.as_str());
+                        assert_eq!(
+                            sorted_result(&r1),
+                            sorted_result(&r2),
+                            "seed={} subject={}",
+                            seed,
+                            subject
+                        );
+                    }
+                }
+                assert_eq!(simple.count(), trie.count());
+            }
+        }
+    }
+    #[test]
+    fn test_count_and_stats() {
+        let mut sl = SimpleSubList::default();
+        let sub = Arc::new(Subscription::new("foo", None, "1", new_test_tcp_writer()));
+        let qsub = Arc::new(Subscription::new("foo", Some("q"), "2", new_test_tcp_writer()));
+        let sub2 = Arc::new(Subscription::new("foo.bar", None, "3", new_test_tcp_writer()));
+        sl.insert(sub.clone()).unwrap();
+        sl.insert(sub.clone()).unwrap();
+        sl.insert(qsub.clone()).unwrap();
+        sl.insert(sub2.clone()).unwrap();
+        assert_eq!(sl.count(), 3);
+        assert_eq!(sl.num_nodes(), 2);
+        assert_eq!(sl.subscriptions().len(), 3);
+        assert_eq!(sl.reverse_match("foo").len(), 2);
+        assert_eq!(sl.reverse_match("foo.*").len(), 1);
+        assert_eq!(sl.reverse_match(">").len(), 3);
+        sl.match_subject("foo");
+        sl.match_subject("foo.bar");
+        sl.remove(qsub).unwrap();
+        assert_eq!(sl.count(), 2);
+        let stats = sl.stats();
+        assert_eq!(stats.num_subscriptions, 2);
+        assert_eq!(stats.num_inserts, 4);
+        assert_eq!(stats.num_removes, 1);
+        assert_eq!(stats.num_matches, 2);
+        assert_eq!(stats.max_fanout, 2);
+        assert_eq!(stats.avg_fanout, 1.5);
+    }
+    #[test]
+    fn test_generation() {
+        let mut sl = SimpleSubList::default();
+        assert_eq!(sl.generation(), 0);
+        let sub = Arc::new(Subscription::new("test", None, "1", new_test_tcp_writer()));
+        sl.insert(sub.clone()).unwrap();
+        assert_eq!(sl.generation(), 1);
+        sl.remove(sub).unwrap();
+        assert_eq!(sl.generation(), 2);
+    }
 }
@@ -0,0 +1,41 @@
+/**
+测试用的内存分配器,统计每个线程净分配了多少内存,
+用来比较不同数据结构的内存占用.
+测试是多线程并发运行的,所以只能按线程统计,
+在一个线程中分配,另一个线程中释放的内存会统计错,比较的时候要避免这种情况.
+*/
+use jemallocator::Jemalloc;
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+
+pub struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<isize> = Cell::new(0);
+}
+fn add(n: isize) {
+    //线程退出的时候thread_local可能已经销毁了,这时候就不统计了
+    let _ = ALLOCATED.try_with(|a| a.set(a.get() + n));
+}
+//当前线程净分配的字节数
+pub fn allocated() -> isize {
+    ALLOCATED.with(|a| a.get())
+}
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        add(layout.size() as isize);
+        Jemalloc.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        add(-(layout.size() as isize));
+        Jemalloc.dealloc(ptr, layout)
+    }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        add(layout.size() as isize);
+        Jemalloc.alloc_zeroed(layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        add(new_size as isize - layout.size() as isize);
+        Jemalloc.realloc(ptr, layout, new_size)
+    }
+}